@@ -0,0 +1,5 @@
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS execution_mode TEXT NOT NULL DEFAULT 'shell';
+
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS argv TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,11 @@
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS env JSONB NOT NULL DEFAULT '{}';
+
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS working_dir TEXT;
+
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS clear_env BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS stdin TEXT;
@@ -0,0 +1,8 @@
+ALTER TABLE workflows.workflows
+    ADD COLUMN IF NOT EXISTS retry_policy JSONB;
+
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS max_retries INTEGER NOT NULL DEFAULT 5;
+
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS retry_policy JSONB;
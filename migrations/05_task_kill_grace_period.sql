@@ -0,0 +1,2 @@
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS kill_grace_period INTERVAL NOT NULL DEFAULT INTERVAL '10 seconds';
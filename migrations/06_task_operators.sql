@@ -0,0 +1,5 @@
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS kind TEXT NOT NULL DEFAULT 'shell';
+
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS config JSONB;
@@ -0,0 +1,2 @@
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS limits JSONB;
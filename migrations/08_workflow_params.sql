@@ -0,0 +1,2 @@
+ALTER TABLE workflows.workflows
+    ADD COLUMN IF NOT EXISTS params JSONB;
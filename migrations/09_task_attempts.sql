@@ -0,0 +1,20 @@
+-- History lives in its own schema, which 00_setup_workflows_schema.sql does not drop,
+-- so it survives restarts.
+CREATE SCHEMA IF NOT EXISTS history;
+
+CREATE TABLE IF NOT EXISTS history.task_attempts (
+    workflow TEXT NOT NULL,
+    run_id TEXT NOT NULL,
+    task_id INTEGER NOT NULL,
+    task_name TEXT NOT NULL,
+    attempt INTEGER NOT NULL,
+    start_time TIMESTAMPTZ NOT NULL,
+    end_time TIMESTAMPTZ,
+    outcome JSONB,
+    exit_code INTEGER,
+    signal INTEGER,
+    failure_reason TEXT,
+    stdout_log TEXT NOT NULL,
+    stderr_log TEXT NOT NULL,
+    PRIMARY KEY (workflow, run_id, task_id, attempt)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS trigger_rule TEXT NOT NULL DEFAULT 'all_success';
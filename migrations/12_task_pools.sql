@@ -0,0 +1,5 @@
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS pool TEXT;
+
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS pool_slots INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,5 @@
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS priority_weight INTEGER NOT NULL DEFAULT 1;
+
+ALTER TABLE workflows.tasks
+    ADD COLUMN IF NOT EXISTS weight_rule TEXT NOT NULL DEFAULT 'absolute';
@@ -0,0 +1,28 @@
+CREATE TABLE IF NOT EXISTS history.workflow_runs (
+    workflow TEXT NOT NULL,
+    run_id TEXT NOT NULL,
+    trigger TEXT NOT NULL,
+    logical_date TIMESTAMPTZ NOT NULL,
+    data_interval_start TIMESTAMPTZ NOT NULL,
+    data_interval_end TIMESTAMPTZ NOT NULL,
+    params JSONB NOT NULL DEFAULT '{}',
+    state TEXT NOT NULL,
+    start_time TIMESTAMPTZ NOT NULL,
+    end_time TIMESTAMPTZ,
+    PRIMARY KEY (workflow, run_id)
+);
+
+CREATE INDEX IF NOT EXISTS workflow_runs_start_time ON history.workflow_runs (workflow, start_time DESC);
+
+CREATE TABLE IF NOT EXISTS history.task_instances (
+    workflow TEXT NOT NULL,
+    run_id TEXT NOT NULL,
+    task_id INTEGER NOT NULL,
+    task_name TEXT NOT NULL,
+    state TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    start_time TIMESTAMPTZ,
+    end_time TIMESTAMPTZ,
+    output JSONB,
+    PRIMARY KEY (workflow, run_id, task_id)
+);
@@ -0,0 +1,4 @@
+ALTER TABLE workflows.workflows
+    ADD COLUMN IF NOT EXISTS catchup BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE workflows.workflows
+    ADD COLUMN IF NOT EXISTS max_active_runs INTEGER;
@@ -0,0 +1,10 @@
+ALTER TABLE workflows.workflows
+    ADD COLUMN IF NOT EXISTS params_schema JSONB;
+
+CREATE TABLE IF NOT EXISTS history.idempotency_keys (
+    workflow TEXT NOT NULL,
+    key TEXT NOT NULL,
+    run_id TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (workflow, key)
+);
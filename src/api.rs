@@ -1,22 +1,67 @@
-use crate::workflow::Workflow;
-use axum::{debug_handler, extract::Path, http::StatusCode, response::IntoResponse, Json, Extension, Router, routing::get, routing::post};
+use crate::workflow::{SharedWorkflows, Workflow};
+use crate::scheduler::Scheduler;
+use crate::backfill::{BackfillInterval, BackfillRequest, Backfills, IntervalState, MAX_BACKFILL_RUNS};
+use axum::{debug_handler, extract::{Path, Query}, http::StatusCode, response::IntoResponse, response::sse::{Event, KeepAlive, Sse}, Json, Extension, Router, routing::get, routing::post, routing::put};
 use serde_json::json;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing::info;
+use tracing::{error, info};
 use futures::future::join_all;
 use tower_http::cors::{CorsLayer, AllowOrigin, Any};
 use crate::task::NewTask;
+use crate::state::TaskState;
+use crate::retry::RetryPolicy;
+use crate::cancel::Cancellations;
+use crate::pool::Pools;
+use crate::history::History;
+use crate::logs::{is_valid_run_id, LogRange, LogStore, LogStream};
+use crate::template::{parse_date, run_id, unique_run_id, RunContext};
+use crate::run::RunTrigger;
+use chrono::Utc;
+use std::collections::{BTreeMap, HashSet};
+use tokio::sync::Mutex;
+use serde::Deserialize;
+use std::io;
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
 
-pub fn create_app(workflows: Arc<Mutex<Vec<Arc<Mutex<Workflow>>>>>) -> Router {
+pub fn create_app(
+    workflows: SharedWorkflows,
+    logs: LogStore,
+    cancellations: Cancellations,
+    pools: Pools,
+    history: History,
+    scheduler: Scheduler,
+    backfills: Backfills,
+) -> Router {
     Router::new()
         .route("/workflows", get(list_tasks))
         .route("/workflow/:workflow_id/task/:id", get(get_task))
+        .route("/workflow/:workflow_id/task/:id/output", get(get_task_output))
+        .route("/workflow/:workflow_id/task/:id/render", get(render_task))
+        .route("/workflow/:workflow_id/task/:id/logs", get(get_task_logs))
+        .route("/workflow/:workflow_id/task/:id/logs/stream", get(stream_task_logs))
+        .route("/workflow/:workflow_id/task/:id/cancel", post(cancel_task))
+        .route("/workflow/:workflow_id/cancel", post(cancel_workflow))
         .route("/workflow/:workflow_id/status", get(get_workflow_status))
         .route("/workflow/graph", get(get_workflow_graph))
         .route("/workflow/:workflow_id/timeline", get(get_execution_timeline)) // Add this line
+        .route("/workflow/:workflow_id/runs", get(list_runs))
+        .route("/workflow/:workflow_id/runs/:run_id", get(get_run))
+        .route("/workflow/:workflow_id/backfills", get(list_backfills).post(start_backfill))
+        .route("/workflow/:workflow_id/backfills/:backfill_id", get(get_backfill))
+        .route("/workflow/:workflow_id/backfills/:backfill_id/cancel", post(cancel_backfill))
+        .route("/workflow/:workflow_id/trigger", post(trigger_run))
         .route("/workflow/task/add", post(add_task))
+        .route("/workflow/:workflow_id/retry_policy", get(get_retry_policy).put(set_retry_policy))
+        .route("/pools", get(list_pools))
+        .route("/pools/:name", put(set_pool))
         .layer(Extension(workflows))
+        .layer(Extension(logs))
+        .layer(Extension(cancellations))
+        .layer(Extension(pools))
+        .layer(Extension(history))
+        .layer(Extension(scheduler))
+        .layer(Extension(backfills))
         .layer(
             CorsLayer::new()
                 .allow_origin(AllowOrigin::exact(
@@ -27,8 +72,16 @@ pub fn create_app(workflows: Arc<Mutex<Vec<Arc<Mutex<Workflow>>>>>) -> Router {
         )
 }
 
-pub async fn run_server(workflows: Arc<Mutex<Vec<Arc<Mutex<Workflow>>>>>) {
-    let app = create_app(workflows);
+pub async fn run_server(
+    workflows: SharedWorkflows,
+    logs: LogStore,
+    cancellations: Cancellations,
+    pools: Pools,
+    history: History,
+    scheduler: Scheduler,
+    backfills: Backfills,
+) {
+    let app = create_app(workflows, logs, cancellations, pools, history, scheduler, backfills);
 
     let addr = "0.0.0.0:3000".parse().unwrap();
     info!("Listening on http://{}", addr);
@@ -41,7 +94,7 @@ pub async fn run_server(workflows: Arc<Mutex<Vec<Arc<Mutex<Workflow>>>>>) {
 
 
 pub async fn list_tasks(
-    Extension(workflows): Extension<Arc<Mutex<Vec<Arc<Mutex<Workflow>>>>>>,
+    Extension(workflows): Extension<SharedWorkflows>,
 ) -> impl IntoResponse {
     let workflows = workflows.lock().await;
     
@@ -64,61 +117,394 @@ pub async fn list_tasks(
     Json(all_tasks)
 }
 
+/// Returns a task of a workflow with its state, attempts and output in a run, the latest one
+/// unless `run_id` is given.
 pub async fn get_task(
     Path((workflow_id, task_id)): Path<(usize, usize)>,
-    Extension(workflows): Extension<Arc<Mutex<Vec<Arc<Mutex<Workflow>>>>>>,
+    Query(query): Query<RunQuery>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Extension(history): Extension<History>,
 ) -> impl IntoResponse {
-    let workflows = workflows.lock().await;
+    let workflow = match workflow_in_run(&workflows, &history, workflow_id, query.run_id).await {
+        Ok(workflow) => workflow,
+        Err(response) => return response,
+    };
 
-    if let Some(workflow) = workflows.get(workflow_id) {
-        let workflow = workflow.lock().await;
-        if let Some(task) = workflow.graph.node_weights().find(|task| task.id == task_id) {
-            return Json(json!({
-                "workflow_id": workflow_id,
-                "task_id": task.id,
-                "name": task.name,
-                "command": task.command,
-                "state": format!("{:?}", task.state),
-            }))
-            .into_response();
-        }
+    if let Some(task) = workflow.graph.node_weights().find(|task| task.id == task_id) {
+        return Json(json!({
+            "workflow_id": workflow_id,
+            "run_id": workflow.run_id,
+            "task_id": task.id,
+            "name": task.name,
+            "kind": task.kind,
+            "config": task.config,
+            "command": task.command,
+            "mode": task.mode,
+            "argv": task.argv,
+            "env": task.env,
+            "working_dir": task.working_dir,
+            "clear_env": task.clear_env,
+            "limits": task.limits,
+            "trigger_rule": task.trigger_rule,
+            "pool": task.pool,
+            "pool_slots": task.pool_slots,
+            "priority_weight": task.priority_weight,
+            "weight_rule": task.weight_rule,
+            "max_retries": task.max_retries,
+            "retry_policy": task.retry_policy.as_ref().unwrap_or(&workflow.default_retry_policy),
+            "timeout_duration": task.timeout_duration,
+            "kill_grace_period": task.kill_grace_period,
+            "attempts": task.attempts,
+            "last_outcome": task.last_outcome,
+            "output": task.output,
+            "state": format!("{:?}", task.state),
+        }))
+        .into_response();
     }
 
     (StatusCode::NOT_FOUND, Json(json!({ "error": "Task not found" }))).into_response()
 }
 
+/// Query parameter picking the run to look at. Defaults to the run the workflow's task states
+/// are from, its latest one.
+#[derive(Deserialize)]
+pub struct RunQuery {
+    pub run_id: Option<String>,
+}
 
-pub async fn get_workflow_status(
-    Path(workflow_id): Path<usize>,
-    Extension(workflows): Extension<Arc<Mutex<Vec<Arc<Mutex<Workflow>>>>>>,
+/// Returns the output a task published in its most recent attempt of a run, or `null` if it
+/// has none. Runs other than the latest one are looked up in the run history.
+///
+/// # Example Response
+/// ```json
+/// {
+///     "workflow_id": 0,
+///     "run_id": "scheduled__2024-03-01T00:00:00",
+///     "task_id": 2,
+///     "name": "Extract",
+///     "state": "Success",
+///     "output": {"rows": 42, "path": "/data/extract.csv"}
+/// }
+/// ```
+pub async fn get_task_output(
+    Path((workflow_id, task_id)): Path<(usize, usize)>,
+    Query(query): Query<RunQuery>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Extension(history): Extension<History>,
+) -> impl IntoResponse {
+    let workflow = match workflow_in_run(&workflows, &history, workflow_id, query.run_id).await {
+        Ok(workflow) => workflow,
+        Err(response) => return response,
+    };
+
+    if let Some(task) = workflow.graph.node_weights().find(|task| task.id == task_id) {
+        return Json(json!({
+            "workflow_id": workflow_id,
+            "run_id": workflow.run_id,
+            "task_id": task.id,
+            "name": task.name,
+            "state": format!("{:?}", task.state),
+            "output": task.output,
+        }))
+        .into_response();
+    }
+
+    (StatusCode::NOT_FOUND, Json(json!({ "error": "Task not found" }))).into_response()
+}
+
+/// Query parameters for `render_task`. `logical_date` defaults to now.
+#[derive(Deserialize)]
+pub struct RenderQuery {
+    pub logical_date: Option<String>,
+}
+
+/// Shows what a task would run with its templates rendered, for a run with the given logical
+/// date and the current outputs of its upstream tasks.
+///
+/// # Example Response
+/// ```json
+/// {
+///     "workflow_id": 0,
+///     "task_id": 2,
+///     "command": "./export.sh --table sales --date 2024-03-01",
+///     "argv": [],
+///     "env": {"PARTITION": "20240301"},
+///     "stdin": null,
+///     "config": null,
+///     "variables": {"ds": "2024-03-01", "params": {"table": "sales"}, "...": "..."}
+/// }
+/// ```
+pub async fn render_task(
+    Path((workflow_id, task_id)): Path<(usize, usize)>,
+    Query(query): Query<RenderQuery>,
+    Extension(workflows): Extension<SharedWorkflows>,
 ) -> impl IntoResponse {
+    let logical_date = match query.logical_date.as_deref().map(parse_date).transpose() {
+        Ok(date) => date.unwrap_or_else(Utc::now),
+        Err(err) => return (StatusCode::BAD_REQUEST, Json(json!({ "error": err }))).into_response(),
+    };
+
     let workflows = workflows.lock().await;
+    let Some(workflow) = workflows.get(workflow_id) else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response();
+    };
+    let workflow = workflow.lock().await;
+    let Some(node) = workflow.graph.node_indices().find(|&node| workflow.graph[node].id == task_id) else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Task not found" }))).into_response();
+    };
 
-    if let Some(workflow) = workflows.get(workflow_id) {
-        let workflow = workflow.lock().await;
-        let states: Vec<_> = workflow
-            .graph
-            .node_weights()
-            .map(|task| format!("{:?}", task.state))
-            .collect();
+    let task = &workflow.graph[node];
+    let upstream: BTreeMap<_, _> = workflow
+        .graph
+        .neighbors_directed(node, petgraph::Incoming)
+        .filter_map(|dep| {
+            let dep = &workflow.graph[dep];
+            dep.output.clone().map(|output| (dep.name.clone(), output))
+        })
+        .collect();
+    let run = RunContext::manual(&workflow.name, &workflow.params, logical_date);
+    let variables = run.variables(task, &upstream);
 
-        let status = if states.iter().all(|s| s == "Success") {
-            "Completed"
-        } else if states.contains(&"Failure".to_string()) {
-            "Failed"
-        } else {
-            "In Progress"
-        };
+    match task.render(&variables) {
+        Ok(rendered) => Json(json!({
+            "workflow_id": workflow_id,
+            "task_id": task_id,
+            "command": rendered.command,
+            "argv": rendered.argv,
+            "env": rendered.env,
+            "stdin": rendered.stdin,
+            "config": rendered.config,
+            "variables": variables,
+        }))
+        .into_response(),
+        Err(err) => (StatusCode::UNPROCESSABLE_ENTITY, Json(json!({ "error": err }))).into_response(),
+    }
+}
 
-        return Json(json!({
+/// Query parameters for `get_task_logs`.
+///
+/// `run_id` defaults to the workflow's latest run and `attempt` to the latest attempt of the
+/// task in that run with stored logs. Either `tail` (last N bytes) or `offset`/`length` can be
+/// used to fetch part of the log.
+#[derive(Deserialize)]
+pub struct LogQuery {
+    pub run_id: Option<String>,
+    pub attempt: Option<usize>,
+    #[serde(default)]
+    pub stream: LogStream,
+    pub offset: Option<u64>,
+    pub length: Option<u64>,
+    pub tail: Option<u64>,
+}
+
+/// Returns the stored stdout or stderr of one attempt of a task in a run.
+///
+/// # Example Response
+/// ```json
+/// {
+///     "workflow_id": 0,
+///     "run_id": "manual__2024-03-01T09:30:00",
+///     "task_id": 3,
+///     "attempt": 2,
+///     "stream": "stderr",
+///     "size": 42,
+///     "offset": 0,
+///     "content": "psql: error: connection refused\n"
+/// }
+/// ```
+pub async fn get_task_logs(
+    Path((workflow_id, task_id)): Path<(usize, usize)>,
+    Query(query): Query<LogQuery>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Extension(logs): Extension<LogStore>,
+) -> impl IntoResponse {
+    let run_id = match run_or_latest(&workflows, workflow_id, query.run_id).await {
+        Ok(run_id) => run_id,
+        Err(response) => return response,
+    };
+    let attempt = match query.attempt {
+        Some(attempt) => attempt,
+        None => match logs.latest_attempt(workflow_id, &run_id, task_id).await {
+            Ok(Some(attempt)) => attempt,
+            Ok(None) => {
+                return (StatusCode::NOT_FOUND, Json(json!({ "error": "No logs found for task" }))).into_response();
+            }
+            Err(err) => {
+                return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": err.to_string() }))).into_response();
+            }
+        },
+    };
+
+    let range = LogRange {
+        offset: query.offset,
+        length: query.length,
+        tail: query.tail,
+    };
+
+    match logs.read(workflow_id, &run_id, task_id, attempt, query.stream, &range).await {
+        Ok(chunk) => Json(json!({
             "workflow_id": workflow_id,
-            "status": status,
-            "tasks": states,
+            "run_id": run_id,
+            "task_id": task_id,
+            "attempt": attempt,
+            "stream": query.stream,
+            "size": chunk.size,
+            "offset": chunk.offset,
+            "content": chunk.content,
         }))
-        .into_response();
+        .into_response(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            (StatusCode::NOT_FOUND, Json(json!({ "error": "No logs found for attempt" }))).into_response()
+        }
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": err.to_string() }))).into_response(),
     }
+}
 
-    (StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response()
+/// Streams the output of a task running in a run, the workflow's latest one by default, as
+/// Server-Sent Events.
+///
+/// Each line is sent as an event named after its stream (`stdout` or `stderr`) with a JSON
+/// payload such as `{"attempt": 1, "stream": "stdout", "line": "loaded 500 rows"}`. Only
+/// output produced after subscribing is sent; earlier output can be fetched from
+/// `get_task_logs`. The stream ends when the task finishes.
+pub async fn stream_task_logs(
+    Path((workflow_id, task_id)): Path<(usize, usize)>,
+    Query(query): Query<RunQuery>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Extension(logs): Extension<LogStore>,
+) -> impl IntoResponse {
+    let run_id = match run_or_latest(&workflows, workflow_id, query.run_id).await {
+        Ok(run_id) => run_id,
+        Err(response) => return response,
+    };
+    let Some(receiver) = logs.subscribe(workflow_id, &run_id, task_id) else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Task is not running" }))).into_response();
+    };
+
+    let events = futures::stream::unfold(receiver, |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(line) => Event::default()
+                .event(line.stream.to_string())
+                .json_data(&line)
+                .unwrap_or_else(|_| Event::default().event("error")),
+            // The subscriber fell behind and missed some lines
+            Err(RecvError::Lagged(skipped)) => Event::default().event("lagged").data(skipped.to_string()),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok::<_, Infallible>(event), receiver))
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+/// Cancels a task of a workflow in one of its runs going on, the latest one unless `run_id` is given.
+///
+/// A running task has its process group terminated and is not retried; a task that has not
+/// started yet will not run. Either way the task and everything downstream of it end up `Cancelled`.
+/// The workflow itself is not locked, so this works while the workflow is running.
+pub async fn cancel_task(
+    Path((workflow_id, task_id)): Path<(usize, usize)>,
+    Query(query): Query<RunQuery>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Extension(cancellations): Extension<Cancellations>,
+) -> impl IntoResponse {
+    let run_id = match run_or_active(&workflows, workflow_id, query.run_id).await {
+        Ok(run_id) => run_id,
+        Err(response) => return response,
+    };
+
+    if !cancellations.cancel_task(workflow_id, &run_id, task_id) {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Run is not going on" }))).into_response();
+    }
+    info!("Cancellation requested for task {} of run '{}' of workflow {}", task_id, run_id, workflow_id);
+
+    (StatusCode::ACCEPTED, Json(json!({
+        "message": "Task cancellation requested",
+        "workflow_id": workflow_id,
+        "run_id": run_id,
+        "task_id": task_id,
+    })))
+    .into_response()
+}
+
+/// Cancels a run of a workflow, the latest one going on unless `run_id` is given, stopping all
+/// of its running and pending tasks. Other runs of the workflow go on. A run that is still
+/// queued never starts.
+pub async fn cancel_workflow(
+    Path(workflow_id): Path<usize>,
+    Query(query): Query<RunQuery>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Extension(cancellations): Extension<Cancellations>,
+) -> impl IntoResponse {
+    let run_id = match run_or_active(&workflows, workflow_id, query.run_id).await {
+        Ok(run_id) => run_id,
+        Err(response) => return response,
+    };
+
+    if !cancellations.cancel_run(workflow_id, &run_id) {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Run is not going on" }))).into_response();
+    }
+    info!("Cancellation requested for run '{}' of workflow {}", run_id, workflow_id);
+
+    (StatusCode::ACCEPTED, Json(json!({
+        "message": "Workflow cancellation requested",
+        "workflow_id": workflow_id,
+        "run_id": run_id,
+    })))
+    .into_response()
+}
+
+/// Returns the status of a workflow and its tasks in a run, the latest one unless `run_id` is
+/// given, with when its schedule fires.
+pub async fn get_workflow_status(
+    Path(workflow_id): Path<usize>,
+    Query(query): Query<RunQuery>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Extension(history): Extension<History>,
+) -> impl IntoResponse {
+    let workflow = match workflow_in_run(&workflows, &history, workflow_id, query.run_id).await {
+        Ok(workflow) => workflow,
+        Err(response) => return response,
+    };
+
+    let states: Vec<_> = workflow
+        .graph
+        .node_weights()
+        .map(|task| format!("{:?}", task.state))
+        .collect();
+
+    let tasks: Vec<_> = workflow.graph.node_weights().map(|task| &task.state).collect();
+    let active = tasks
+        .iter()
+        .any(|state| matches!(state, TaskState::Queued | TaskState::Running | TaskState::UpForRetry));
+
+    // Tasks skipped by their trigger rule don't keep a workflow from completing
+    let status = if tasks.iter().all(|state| matches!(state, TaskState::Success | TaskState::Skipped)) {
+        "Completed"
+    } else if tasks.iter().any(|state| state.is_failed()) {
+        "Failed"
+    } else if tasks.contains(&&TaskState::Cancelled) && !active {
+        "Cancelled"
+    } else {
+        "In Progress"
+    };
+
+    // When the schedule fires next and last fired, which is when runs start
+    let now = Utc::now();
+    let schedule = workflow.schedule().ok().flatten();
+    let next_run = schedule.as_ref().and_then(|schedule| schedule.next_after(&now));
+    let previous_run = schedule.as_ref().and_then(|schedule| schedule.previous_before(&now));
+
+    Json(json!({
+        "workflow_id": workflow_id,
+        "run_id": workflow.run_id,
+        "status": status,
+        "tasks": states,
+        "schedule": workflow.schedule,
+        "next_run": next_run,
+        "previous_run": previous_run,
+    }))
+    .into_response()
 }
 
 /// Generates a graph representation of all workflows.
@@ -152,7 +538,7 @@ pub async fn get_workflow_status(
 /// }
 /// ```
 pub async fn get_workflow_graph(
-    Extension(workflows): Extension<Arc<Mutex<Vec<Arc<Mutex<Workflow>>>>>>,
+    Extension(workflows): Extension<SharedWorkflows>,
 ) -> Json<serde_json::Value> {
     let workflows = workflows.lock().await;
 
@@ -192,42 +578,62 @@ pub async fn get_workflow_graph(
 }
 
 
+/// Returns the attempts of every task of a workflow in a run, the latest one unless `run_id`
+/// is given, for drawing them on a timeline.
 pub async fn get_execution_timeline(
     Path(workflow_id): Path<usize>,
-    Extension(workflows): Extension<Arc<Mutex<Vec<Arc<Mutex<Workflow>>>>>>,
+    Query(query): Query<RunQuery>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Extension(history): Extension<History>,
 ) -> impl IntoResponse {
-    let workflows = workflows.lock().await;
+    let workflow = match workflow_in_run(&workflows, &history, workflow_id, query.run_id).await {
+        Ok(workflow) => workflow,
+        Err(response) => return response,
+    };
 
-    if let Some(workflow) = workflows.get(workflow_id) {
-        let workflow = workflow.lock().await;
-        let timeline: Vec<_> = workflow.graph.node_weights().map(|task| {
-            json!({
+    // One bar per attempt, or per task for tasks that never made one
+    let timeline: Vec<_> = workflow.graph.node_weights().flat_map(|task| {
+        if task.attempts.is_empty() {
+            return vec![json!({
                 "task_id": task.id,
                 "name": task.name,
+                "attempt": null,
                 "start_time": task.start_time,
                 "end_time": task.end_time,
-                "duration": task.end_time.map(|end| (end - task.start_time.unwrap_or(end)).num_seconds())
+                "duration": task.end_time.map(|end| (end - task.start_time.unwrap_or(end)).num_seconds()),
+                "outcome": task.last_outcome,
+                "failure_reason": task.last_outcome.as_ref().and_then(|outcome| outcome.failure_reason()),
+            })];
+        }
+        task.attempts.iter().map(|attempt| {
+            json!({
+                "task_id": task.id,
+                "name": task.name,
+                "attempt": attempt.number,
+                "start_time": attempt.start_time,
+                "end_time": attempt.end_time,
+                "duration": attempt.end_time.map(|end| (end - attempt.start_time).num_seconds()),
+                "outcome": attempt.outcome,
+                "failure_reason": attempt.failure_reason,
             })
-        }).collect();
-
-        return Json(json!({ "workflow_id": workflow_id, "timeline": timeline })).into_response();
-    }
+        }).collect()
+    }).collect();
 
-    (StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response()
+    Json(json!({ "workflow_id": workflow_id, "run_id": workflow.run_id, "timeline": timeline })).into_response()
 }
 #[debug_handler]
 pub async fn add_task(
-    Extension(workflows): Extension<Arc<tokio::sync::Mutex<Vec<Arc<Mutex<Workflow>>>>>>,
+    Extension(workflows): Extension<SharedWorkflows>,
     Json(new_task): Json<NewTask>,
 ) -> impl IntoResponse {
     let workflows = workflows.lock().await;
 
     // For simplicity, we'll add the task to the first workflow.
-    if let Some(workflow) = workflows.get(0) {
+    if let Some(workflow) = workflows.first() {
         let mut workflow = workflow.lock().await;
 
         // Add the new task.
-        let node_index = workflow.add_task_dynamically(new_task.id, &new_task.name, &new_task.command);
+        let node_index = workflow.add_task(new_task.to_task());
 
         // Add dependencies.
         for dependency_id in new_task.dependencies {
@@ -245,4 +651,498 @@ pub async fn add_task(
     }
 
     (StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response()
-}
\ No newline at end of file
+}
+
+/// Returns the default retry policy of a workflow.
+pub async fn get_retry_policy(
+    Path(workflow_id): Path<usize>,
+    Extension(workflows): Extension<SharedWorkflows>,
+) -> impl IntoResponse {
+    let workflows = workflows.lock().await;
+
+    if let Some(workflow) = workflows.get(workflow_id) {
+        let workflow = workflow.lock().await;
+        return Json(json!({
+            "workflow_id": workflow_id,
+            "retry_policy": workflow.default_retry_policy,
+        }))
+        .into_response();
+    }
+
+    (StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response()
+}
+
+/// Replaces the default retry policy used by tasks of the workflow that don't have their own.
+pub async fn set_retry_policy(
+    Path(workflow_id): Path<usize>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Json(policy): Json<RetryPolicy>,
+) -> impl IntoResponse {
+    let workflows = workflows.lock().await;
+
+    if let Some(workflow) = workflows.get(workflow_id) {
+        let mut workflow = workflow.lock().await;
+        workflow.default_retry_policy = policy;
+        return Json(json!({
+            "workflow_id": workflow_id,
+            "retry_policy": workflow.default_retry_policy,
+        }))
+        .into_response();
+    }
+
+    (StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response()
+}
+
+/// Lists the resource pools with the slots their running tasks take and how many tasks wait for them.
+pub async fn list_pools(Extension(pools): Extension<Pools>) -> impl IntoResponse {
+    Json(json!({ "pools": pools.status() }))
+}
+
+#[derive(Deserialize)]
+pub struct PoolRequest {
+    pub slots: usize,
+}
+
+/// Creates a resource pool or changes its number of slots.
+pub async fn set_pool(
+    Path(name): Path<String>,
+    Extension(pools): Extension<Pools>,
+    Json(request): Json<PoolRequest>,
+) -> impl IntoResponse {
+    info!("Setting pool '{}' to {} slots", name, request.slots);
+    Json(pools.set(&name, request.slots))
+}
+
+/// Finds a workflow given either its id or its name, and returns it with its id.
+async fn find_workflow(workflows: &SharedWorkflows, workflow: &str) -> Option<(usize, Arc<Mutex<Workflow>>)> {
+    let workflows = workflows.lock().await;
+    if let Some(id) = workflow.parse::<usize>().ok().filter(|id| *id < workflows.len()) {
+        return Some((id, Arc::clone(&workflows[id])));
+    }
+    for (id, candidate) in workflows.iter().enumerate() {
+        if candidate.lock().await.name == workflow {
+            return Some((id, Arc::clone(candidate)));
+        }
+    }
+    None
+}
+
+/// Returns a copy of a workflow with its tasks as they were in the run `run_id`, looked up in
+/// `history`, or as they are in its latest run if no other run is given.
+async fn workflow_in_run(
+    workflows: &SharedWorkflows,
+    history: &History,
+    workflow_id: usize,
+    run_id: Option<String>,
+) -> Result<Workflow, axum::response::Response> {
+    let Some(workflow) = workflows.lock().await.get(workflow_id).cloned() else {
+        return Err((StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response());
+    };
+    let mut workflow = workflow.lock().await.clone();
+    let Some(run_id) = run_id.filter(|run_id| Some(run_id) != workflow.run_id.as_ref()) else {
+        return Ok(workflow);
+    };
+
+    let internal_error = |err: sqlx::Error| {
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": err.to_string() }))).into_response()
+    };
+    let Some((_, instances)) = history.run(&workflow.name, &run_id).await.map_err(internal_error)? else {
+        return Err((StatusCode::NOT_FOUND, Json(json!({ "error": "Run not found" }))).into_response());
+    };
+    let attempts = history.attempts(&workflow.name, &run_id).await.map_err(internal_error)?;
+
+    for task in workflow.graph.node_weights_mut() {
+        // Tasks the run never got to are left pending
+        task.reset();
+        if let Some(instance) = instances.iter().find(|instance| instance.task_id == task.id) {
+            task.state = instance.state.clone();
+            task.start_time = instance.start_time;
+            task.end_time = instance.end_time;
+            task.output = instance.output.clone();
+        }
+        task.attempts = attempts
+            .iter()
+            .filter(|(task_id, _)| *task_id == task.id)
+            .map(|(_, attempt)| attempt.clone())
+            .collect();
+        task.retry_count = task.attempts.len().saturating_sub(1);
+        task.last_outcome = task.attempts.last().and_then(|attempt| attempt.outcome.clone());
+    }
+    workflow.run_id = Some(run_id);
+    Ok(workflow)
+}
+
+/// Returns `run_id` if given, or else the workflow's latest run, failing with the response to
+/// send if the workflow does not exist or has not run yet, or if `run_id` could lead out of the
+/// log store.
+async fn run_or_latest(
+    workflows: &SharedWorkflows,
+    workflow_id: usize,
+    run_id: Option<String>,
+) -> Result<String, axum::response::Response> {
+    let Some(workflow) = workflows.lock().await.get(workflow_id).cloned() else {
+        return Err((StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response());
+    };
+    if let Some(run_id) = run_id.as_deref().filter(|run_id| !is_valid_run_id(run_id)) {
+        let error = format!("Invalid run id '{}'", run_id);
+        return Err((StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response());
+    }
+    let latest = workflow.lock().await.run_id.clone();
+    run_id
+        .or(latest)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow has not run yet" }))).into_response())
+}
+
+/// Returns `run_id` if given, or else the latest run of the workflow going on, failing with
+/// the response to send if the workflow does not exist or has no run going on.
+async fn run_or_active(
+    workflows: &SharedWorkflows,
+    workflow_id: usize,
+    run_id: Option<String>,
+) -> Result<String, axum::response::Response> {
+    let Some(workflow) = workflows.lock().await.get(workflow_id).cloned() else {
+        return Err((StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response());
+    };
+    let active = workflow.lock().await.active_runs.last().cloned();
+    run_id
+        .or(active)
+        .ok_or_else(|| (StatusCode::CONFLICT, Json(json!({ "error": "Workflow has no run going on" }))).into_response())
+}
+
+/// Finds the name of a workflow given either its id or its name, which is how its runs are stored.
+async fn find_workflow_name(workflows: &SharedWorkflows, workflow: &str) -> Option<String> {
+    let (_, workflow) = find_workflow(workflows, workflow).await?;
+    let name = workflow.lock().await.name.clone();
+    Some(name)
+}
+
+#[derive(Deserialize)]
+pub struct RunsQuery {
+    /// Maximum number of runs to return.
+    #[serde(default = "default_runs_limit")]
+    pub limit: i64,
+}
+
+fn default_runs_limit() -> i64 {
+    50
+}
+
+/// Lists the runs of a workflow, given by id or name, most recent first.
+pub async fn list_runs(
+    Path(workflow): Path<String>,
+    Query(query): Query<RunsQuery>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Extension(history): Extension<History>,
+) -> impl IntoResponse {
+    let Some(name) = find_workflow_name(&workflows, &workflow).await else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response();
+    };
+
+    match history.runs(&name, query.limit.max(0)).await {
+        Ok(runs) => Json(json!({ "workflow": name, "runs": runs })).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": err.to_string() }))).into_response(),
+    }
+}
+
+/// Returns a run of a workflow with the state and attempts of each of its tasks.
+pub async fn get_run(
+    Path((workflow, run_id)): Path<(String, String)>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Extension(history): Extension<History>,
+) -> impl IntoResponse {
+    let Some(name) = find_workflow_name(&workflows, &workflow).await else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response();
+    };
+
+    let (run, tasks) = match history.run(&name, &run_id).await {
+        Ok(Some(run)) => run,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(json!({ "error": "Run not found" }))).into_response(),
+        Err(err) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": err.to_string() }))).into_response();
+        }
+    };
+    let attempts = match history.attempts(&name, &run_id).await {
+        Ok(attempts) => attempts,
+        Err(err) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": err.to_string() }))).into_response();
+        }
+    };
+
+    let tasks: Vec<_> = tasks
+        .into_iter()
+        .map(|task| {
+            let task_attempts: Vec<_> = attempts
+                .iter()
+                .filter(|(task_id, _)| *task_id == task.task_id)
+                .map(|(_, attempt)| attempt)
+                .collect();
+            json!({
+                "task_id": task.task_id,
+                "name": task.name,
+                "state": task.state,
+                "start_time": task.start_time,
+                "end_time": task.end_time,
+                "output": task.output,
+                "attempts": task_attempts,
+            })
+        })
+        .collect();
+
+    Json(json!({ "run": run, "tasks": tasks })).into_response()
+}
+
+/// Starts a backfill of a workflow, given by id or name: a run for each of its schedule
+/// intervals between two dates, skipping intervals that already succeeded unless asked to run
+/// them again, and intervals a run is going on for. Returns the backfill straight away, the
+/// runs go on in the background.
+pub async fn start_backfill(
+    Path(workflow): Path<String>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Extension(scheduler): Extension<Scheduler>,
+    Extension(backfills): Extension<Backfills>,
+    Extension(history): Extension<History>,
+    Json(request): Json<BackfillRequest>,
+) -> impl IntoResponse {
+    let Some((workflow_id, workflow)) = find_workflow(&workflows, &workflow).await else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response();
+    };
+    let (name, schedule, save_path) = {
+        let workflow = workflow.lock().await;
+        (workflow.name.clone(), workflow.schedule(), workflow.save_path.clone())
+    };
+    let schedule = match schedule {
+        Ok(Some(schedule)) => schedule,
+        Ok(None) => {
+            let error = "Workflow has no schedule to backfill";
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response();
+        }
+        Err(err) => return (StatusCode::BAD_REQUEST, Json(json!({ "error": err }))).into_response(),
+    };
+    let (start, end) = match (parse_date(&request.start), parse_date(&request.end)) {
+        (Ok(start), Ok(end)) if start < end => (start, end),
+        (Ok(_), Ok(_)) => {
+            let error = "The start of a backfill must be before its end";
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response();
+        }
+        (Err(err), _) | (_, Err(err)) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": err }))).into_response();
+        }
+    };
+
+    let intervals: Vec<_> = schedule.intervals(start, end).take(MAX_BACKFILL_RUNS + 1).collect();
+    if intervals.len() > MAX_BACKFILL_RUNS {
+        let error = format!("A backfill cannot make more than {} runs", MAX_BACKFILL_RUNS);
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response();
+    }
+    let succeeded = if request.rerun_successful {
+        Ok(HashSet::new())
+    } else {
+        history.succeeded_dates(&name, start, end).await
+    };
+    let looked_up = (
+        succeeded,
+        history.active_dates(&name, start, end).await,
+        history.run_ids_starting_with(&name, &format!("{}__", RunTrigger::Backfill)).await,
+    );
+    let (succeeded, active, taken) = match looked_up {
+        (Ok(succeeded), Ok(active), Ok(taken)) => (succeeded, active, taken),
+        (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": err.to_string() }))).into_response();
+        }
+    };
+    let intervals = intervals
+        .into_iter()
+        .map(|(from, to)| BackfillInterval {
+            // Intervals run again get a new run id, so earlier runs stay in the run history
+            run_id: unique_run_id(&run_id(RunTrigger::Backfill, from), &taken),
+            data_interval_start: from,
+            data_interval_end: to,
+            state: if succeeded.contains(&from) {
+                IntervalState::AlreadySucceeded
+            } else if active.contains(&from) {
+                IntervalState::AlreadyRunning
+            } else {
+                IntervalState::Pending
+            },
+        })
+        .collect();
+
+    let backfill = backfills.create(workflow_id, &name, &request, (start, end), intervals);
+    info!(
+        "Backfill {} of workflow '{}': {} runs to make",
+        backfill.id, name, backfill.progress.pending
+    );
+    tokio::spawn({
+        let backfill = backfill.clone();
+        async move {
+            scheduler.backfill(workflow_id, &workflow, &save_path, &backfills, backfill).await;
+        }
+    });
+
+    (StatusCode::ACCEPTED, Json(backfill)).into_response()
+}
+
+/// Lists the backfills of a workflow, given by id or name, most recent first.
+pub async fn list_backfills(
+    Path(workflow): Path<String>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Extension(backfills): Extension<Backfills>,
+) -> impl IntoResponse {
+    let Some((workflow_id, _)) = find_workflow(&workflows, &workflow).await else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response();
+    };
+    Json(json!({ "backfills": backfills.list(workflow_id) })).into_response()
+}
+
+/// Returns a backfill of a workflow with its progress and the state of each of its intervals.
+pub async fn get_backfill(
+    Path((workflow, backfill_id)): Path<(String, usize)>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Extension(backfills): Extension<Backfills>,
+) -> impl IntoResponse {
+    let Some((workflow_id, _)) = find_workflow(&workflows, &workflow).await else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response();
+    };
+    match backfills.get(backfill_id).filter(|backfill| backfill.workflow_id == workflow_id) {
+        Some(backfill) => Json(backfill).into_response(),
+        None => (StatusCode::NOT_FOUND, Json(json!({ "error": "Backfill not found" }))).into_response(),
+    }
+}
+
+/// Cancels a backfill of a workflow: intervals that have not started are not run and the runs
+/// of those going on are cancelled. Returns the backfill, which ends up `cancelled` once those
+/// runs are over.
+pub async fn cancel_backfill(
+    Path((workflow, backfill_id)): Path<(String, usize)>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Extension(cancellations): Extension<Cancellations>,
+    Extension(backfills): Extension<Backfills>,
+) -> impl IntoResponse {
+    let Some((workflow_id, _)) = find_workflow(&workflows, &workflow).await else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response();
+    };
+    if backfills.get(backfill_id).filter(|backfill| backfill.workflow_id == workflow_id).is_none() {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Backfill not found" }))).into_response();
+    }
+
+    for run_id in backfills.cancel(backfill_id).unwrap_or_default() {
+        cancellations.cancel_run(workflow_id, &run_id);
+    }
+    info!("Cancellation requested for backfill {} of workflow {}", backfill_id, workflow_id);
+    (StatusCode::ACCEPTED, Json(backfills.get(backfill_id))).into_response()
+}
+
+/// What to start a run of a workflow with.
+#[derive(Deserialize)]
+pub struct TriggerRequest {
+    /// Params of the run, on top of the workflow's own.
+    #[serde(default)]
+    pub params: serde_json::Map<String, serde_json::Value>,
+    /// The date the run is for, as `YYYY-MM-DD` or an RFC 3339 timestamp. Defaults to now.
+    pub logical_date: Option<String>,
+    /// Requests with the same key start a single run; later ones return that run's id.
+    pub idempotency_key: Option<String>,
+}
+
+/// Starts a run of a workflow, given by id or name, with the given params and returns its id.
+///
+/// The params are checked against the workflow's `params_schema`. A request with an
+/// idempotency key that was already used starts nothing and returns the run started for it.
+pub async fn trigger_run(
+    Path(workflow): Path<String>,
+    Extension(workflows): Extension<SharedWorkflows>,
+    Extension(scheduler): Extension<Scheduler>,
+    Extension(history): Extension<History>,
+    Json(request): Json<TriggerRequest>,
+) -> impl IntoResponse {
+    let Some((workflow_id, workflow)) = find_workflow(&workflows, &workflow).await else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "Workflow not found" }))).into_response();
+    };
+    let logical_date = match request.logical_date.as_deref().map(parse_date).transpose() {
+        Ok(logical_date) => logical_date.unwrap_or_else(Utc::now),
+        Err(err) => return (StatusCode::BAD_REQUEST, Json(json!({ "error": err }))).into_response(),
+    };
+    // The workflow stays locked until the run is registered, so concurrent requests neither
+    // take the same run id nor both start a run for the same idempotency key
+    let mut locked = workflow.lock().await;
+    let mut params = locked.params.clone();
+    params.extend(request.params);
+    if let Some(Err(errors)) = locked.params_schema.as_ref().map(|schema| schema.validate(&params)) {
+        let error = "Invalid params";
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": error, "details": errors }))).into_response();
+    }
+    let mut context = RunContext::manual(&locked.name, &params, logical_date);
+    // Another run for the same logical date gets an id of its own
+    let mut taken = match history.run_ids_starting_with(&context.workflow, &context.run_id).await {
+        Ok(taken) => taken,
+        Err(err) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": err.to_string() }))).into_response();
+        }
+    };
+    taken.extend(locked.active_runs.iter().chain(&locked.starting_runs).cloned());
+    context.run_id = unique_run_id(&context.run_id, &taken);
+    // Requests made again with the same key get the run the first one started
+    if let Some(key) = &request.idempotency_key {
+        match history.claim_idempotency_key(&context.workflow, key, &context.run_id).await {
+            Ok(run_id) if run_id == context.run_id => {}
+            Ok(run_id) => return already_triggered(&context.workflow, key, run_id),
+            Err(err) => {
+                return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": err.to_string() }))).into_response();
+            }
+        }
+    }
+    locked.starting_runs.push(context.run_id.clone());
+    let save_path = locked.save_path.clone();
+    drop(locked);
+
+    info!("Triggering run '{}' of workflow '{}'", context.run_id, context.workflow);
+    let response = json!({
+        "workflow": context.workflow,
+        "run_id": context.run_id,
+        "logical_date": context.logical_date,
+        "created": true,
+    });
+    tokio::spawn(async move {
+        if let Err(err) = scheduler.run(workflow_id, &workflow, &save_path, context).await {
+            error!("Error running workflow {}: {}", workflow_id, err);
+        }
+    });
+
+    (StatusCode::ACCEPTED, Json(response)).into_response()
+}
+
+/// Answers a trigger request whose idempotency key already started `run_id`.
+fn already_triggered(workflow: &str, key: &str, run_id: String) -> axum::response::Response {
+    info!("Idempotency key '{}' of workflow '{}' already started run '{}'", key, workflow, run_id);
+    Json(json!({ "workflow": workflow, "run_id": run_id, "created": false })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn task_logs_cannot_be_read_from_outside_the_log_store() {
+        let dir = std::env::temp_dir().join(format!("thermidor-api-test-{}", std::process::id()));
+        // Where `../../outside` leads from `<root>/workflow_0`
+        let outside = dir.join("outside/task_0/attempt_1");
+        tokio::fs::create_dir_all(&outside).await.unwrap();
+        tokio::fs::write(outside.join("stdout.log"), "secret").await.unwrap();
+        tokio::fs::create_dir_all(dir.join("logs/workflow_0")).await.unwrap();
+
+        let workflows: SharedWorkflows = Arc::new(Mutex::new(vec![Arc::new(Mutex::new(Workflow::new()))]));
+        let logs = LogStore::new(dir.join("logs"));
+        let query = LogQuery {
+            run_id: Some("../../outside".to_string()),
+            attempt: Some(1),
+            stream: LogStream::Stdout,
+            offset: None,
+            length: None,
+            tail: None,
+        };
+        let response = get_task_logs(Path((0, 0)), Query(query), Extension(workflows), Extension(logs))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,420 @@
+use crate::run::RunState;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::fmt;
+
+/// Most runs a single backfill may create.
+pub const MAX_BACKFILL_RUNS: usize = 10_000;
+
+/// What to backfill, as sent to the API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackfillRequest {
+    /// Start of the range, as `YYYY-MM-DD` or an RFC 3339 timestamp.
+    pub start: String,
+    /// End of the range, in the same format as `start`.
+    pub end: String,
+    /// Most runs of the backfill going on at once.
+    #[serde(default = "default_max_active_runs")]
+    pub max_active_runs: usize,
+    /// Runs intervals again even if a run for them already succeeded.
+    #[serde(default)]
+    pub rerun_successful: bool,
+}
+
+fn default_max_active_runs() -> usize {
+    1
+}
+
+/// Where a backfill is at.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackfillState {
+    Running,
+    /// Every interval succeeded, now or before.
+    Success,
+    /// A run failed or was cancelled.
+    Failed,
+    /// The backfill was cancelled before every interval was run.
+    Cancelled,
+}
+
+/// Where one interval of a backfill is at.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IntervalState {
+    /// Waiting for a run.
+    Pending,
+    Running,
+    Success,
+    Failed,
+    Cancelled,
+    /// Not run again, as a run for it already succeeded.
+    AlreadySucceeded,
+    /// Not run, as a run for it was going on, or waiting in another backfill, when the backfill
+    /// started.
+    AlreadyRunning,
+}
+
+impl From<RunState> for IntervalState {
+    fn from(state: RunState) -> Self {
+        match state {
+            RunState::Queued | RunState::Running => IntervalState::Running,
+            RunState::Success => IntervalState::Success,
+            RunState::Failed => IntervalState::Failed,
+            RunState::Cancelled => IntervalState::Cancelled,
+        }
+    }
+}
+
+/// One schedule interval of a backfill, and the run made for it.
+#[derive(Debug, Clone, Serialize)]
+pub struct BackfillInterval {
+    pub run_id: String,
+    pub data_interval_start: DateTime<Utc>,
+    pub data_interval_end: DateTime<Utc>,
+    pub state: IntervalState,
+}
+
+/// Number of intervals of a backfill in each state.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Progress {
+    pub total: usize,
+    pub pending: usize,
+    pub running: usize,
+    pub success: usize,
+    pub failed: usize,
+    pub cancelled: usize,
+    pub already_succeeded: usize,
+    pub already_running: usize,
+    /// Share of the intervals that are done, from 0 to 100.
+    pub percent_done: f64,
+}
+
+impl Progress {
+    fn of(intervals: &[BackfillInterval]) -> Self {
+        let mut progress = Progress { total: intervals.len(), ..Progress::default() };
+        for interval in intervals {
+            match interval.state {
+                IntervalState::Pending => progress.pending += 1,
+                IntervalState::Running => progress.running += 1,
+                IntervalState::Success => progress.success += 1,
+                IntervalState::Failed => progress.failed += 1,
+                IntervalState::Cancelled => progress.cancelled += 1,
+                IntervalState::AlreadySucceeded => progress.already_succeeded += 1,
+                IntervalState::AlreadyRunning => progress.already_running += 1,
+            }
+        }
+        let done = progress.total - progress.pending - progress.running;
+        progress.percent_done = match progress.total {
+            0 => 100.0,
+            total => (done * 100) as f64 / total as f64,
+        };
+        progress
+    }
+}
+
+/// A run of a workflow for each of its schedule intervals over a past date range, so data
+/// that was missed or needs reprocessing gets processed.
+#[derive(Debug, Clone, Serialize)]
+pub struct Backfill {
+    pub id: usize,
+    pub workflow_id: usize,
+    pub workflow: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub max_active_runs: usize,
+    pub rerun_successful: bool,
+    pub state: BackfillState,
+    pub start_time: DateTime<Utc>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub progress: Progress,
+    /// The intervals, oldest first.
+    pub intervals: Vec<BackfillInterval>,
+    /// Whether the backfill was asked to stop.
+    #[serde(skip)]
+    cancelled: bool,
+}
+
+impl Backfill {
+    /// Moves an interval to `state`, and the backfill along with it once every interval is done.
+    pub fn set_state(&mut self, interval: usize, state: IntervalState) {
+        self.intervals[interval].state = state;
+        self.progress = Progress::of(&self.intervals);
+        if self.progress.pending + self.progress.running == 0 {
+            self.state = if self.cancelled {
+                BackfillState::Cancelled
+            } else if self.progress.failed + self.progress.cancelled == 0 {
+                BackfillState::Success
+            } else {
+                BackfillState::Failed
+            };
+            self.end_time = Some(Utc::now());
+        }
+    }
+}
+
+/// The backfills started since the server started, shared between the API and the runs
+/// they make.
+#[derive(Clone, Default)]
+pub struct Backfills {
+    backfills: Arc<Mutex<Vec<Backfill>>>,
+}
+
+impl Backfills {
+    /// Creates an empty list of backfills.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a backfill of `workflow` over `intervals` and returns it. Intervals with an
+    /// `AlreadySucceeded` or `AlreadyRunning` state are not run, nor are pending intervals that
+    /// another backfill of the workflow is still to run or running; those become `AlreadyRunning`.
+    pub fn create(
+        &self,
+        workflow_id: usize,
+        workflow: &str,
+        request: &BackfillRequest,
+        (start, end): (DateTime<Utc>, DateTime<Utc>),
+        mut intervals: Vec<BackfillInterval>,
+    ) -> Backfill {
+        let mut backfills = self.backfills.lock().unwrap();
+        let claimed: HashSet<_> = backfills
+            .iter()
+            .filter(|backfill| backfill.workflow_id == workflow_id && backfill.state == BackfillState::Running)
+            .flat_map(|backfill| &backfill.intervals)
+            .filter(|interval| matches!(interval.state, IntervalState::Pending | IntervalState::Running))
+            .map(|interval| interval.data_interval_start)
+            .collect();
+        for interval in &mut intervals {
+            if interval.state == IntervalState::Pending && claimed.contains(&interval.data_interval_start) {
+                interval.state = IntervalState::AlreadyRunning;
+            }
+        }
+        let mut backfill = Backfill {
+            id: backfills.len(),
+            workflow_id,
+            workflow: workflow.to_string(),
+            start,
+            end,
+            max_active_runs: request.max_active_runs.max(1),
+            rerun_successful: request.rerun_successful,
+            state: BackfillState::Running,
+            start_time: Utc::now(),
+            end_time: None,
+            progress: Progress::of(&intervals),
+            intervals,
+            cancelled: false,
+        };
+        if backfill.progress.pending == 0 {
+            backfill.state = BackfillState::Success;
+            backfill.end_time = Some(backfill.start_time);
+        }
+        backfills.push(backfill.clone());
+        backfill
+    }
+
+    /// Returns a backfill, if there is one with that id.
+    pub fn get(&self, id: usize) -> Option<Backfill> {
+        self.backfills.lock().unwrap().get(id).cloned()
+    }
+
+    /// Returns the backfills of a workflow, most recent first.
+    pub fn list(&self, workflow_id: usize) -> Vec<Backfill> {
+        self.backfills
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|backfill| backfill.workflow_id == workflow_id)
+            .cloned()
+            .collect()
+    }
+
+    /// Moves a pending interval of a backfill to `Running`, unless the backfill was cancelled.
+    /// Returns whether the interval is to be run.
+    pub fn start_interval(&self, id: usize, interval: usize) -> bool {
+        let mut backfills = self.backfills.lock().unwrap();
+        let backfill = &mut backfills[id];
+        if backfill.cancelled || backfill.intervals[interval].state != IntervalState::Pending {
+            return false;
+        }
+        backfill.set_state(interval, IntervalState::Running);
+        true
+    }
+
+    /// Cancels a backfill: its pending intervals are marked `Cancelled` and no more of them
+    /// start. Returns the ids of the runs of its intervals that are going on, for them to be
+    /// cancelled too, or `None` if there is no such backfill.
+    pub fn cancel(&self, id: usize) -> Option<Vec<String>> {
+        let mut backfills = self.backfills.lock().unwrap();
+        let backfill = backfills.get_mut(id)?;
+        if backfill.state != BackfillState::Running {
+            return Some(Vec::new());
+        }
+        backfill.cancelled = true;
+        let mut running = Vec::new();
+        for index in 0..backfill.intervals.len() {
+            match backfill.intervals[index].state {
+                IntervalState::Pending => backfill.set_state(index, IntervalState::Cancelled),
+                IntervalState::Running => running.push(backfill.intervals[index].run_id.clone()),
+                _ => {}
+            }
+        }
+        Some(running)
+    }
+
+    /// Moves an interval of a backfill to `state` and returns the backfill's progress.
+    pub fn set_state(&self, id: usize, interval: usize, state: IntervalState) -> Progress {
+        let mut backfills = self.backfills.lock().unwrap();
+        let backfill = &mut backfills[id];
+        backfill.set_state(interval, state);
+        backfill.progress
+    }
+}
+
+impl fmt::Display for BackfillState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            BackfillState::Running => "running",
+            BackfillState::Success => "success",
+            BackfillState::Failed => "failed",
+            BackfillState::Cancelled => "cancelled",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    fn day(n: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap() + Duration::days(n)
+    }
+
+    /// Daily intervals starting on the given days, in the given states.
+    fn intervals(days: &[(i64, IntervalState)]) -> Vec<BackfillInterval> {
+        days.iter()
+            .map(|&(n, state)| BackfillInterval {
+                run_id: format!("backfill__{}", day(n).format("%Y-%m-%d")),
+                data_interval_start: day(n),
+                data_interval_end: day(n + 1),
+                state,
+            })
+            .collect()
+    }
+
+    fn request(max_active_runs: usize) -> BackfillRequest {
+        BackfillRequest {
+            start: "2024-01-01".to_string(),
+            end: "2024-02-01".to_string(),
+            max_active_runs,
+            rerun_successful: false,
+        }
+    }
+
+    fn create(backfills: &Backfills, workflow_id: usize, days: &[(i64, IntervalState)]) -> Backfill {
+        let range = (day(days[0].0), day(days[days.len() - 1].0 + 1));
+        backfills.create(workflow_id, "daily", &request(2), range, intervals(days))
+    }
+
+    #[test]
+    fn progress_counts_the_intervals_in_each_state() {
+        use IntervalState::*;
+        let progress = Progress::of(&intervals(&[
+            (0, Pending),
+            (1, Running),
+            (2, Success),
+            (3, Failed),
+            (4, Cancelled),
+            (5, AlreadySucceeded),
+            (6, AlreadyRunning),
+            (7, Success),
+        ]));
+        assert_eq!(progress.total, 8);
+        assert_eq!((progress.pending, progress.running, progress.success, progress.failed), (1, 1, 2, 1));
+        assert_eq!((progress.cancelled, progress.already_succeeded, progress.already_running), (1, 1, 1));
+        assert_eq!(progress.percent_done, 75.0);
+        // Nothing to do is all done
+        assert_eq!(Progress::of(&[]).percent_done, 100.0);
+    }
+
+    #[test]
+    fn intervals_another_backfill_still_runs_are_not_claimed_again() {
+        use IntervalState::*;
+        let backfills = Backfills::new();
+        let first = create(&backfills, 0, &[(0, Pending), (1, Pending), (2, AlreadySucceeded)]);
+        assert_eq!(first.state, BackfillState::Running);
+        assert_eq!(first.progress.pending, 2);
+        assert!(backfills.start_interval(first.id, 0));
+
+        // Overlaps the first on days 0 to 2: the running and pending ones are taken, the one
+        // it didn't run is free
+        let second = create(&backfills, 0, &[(0, Pending), (1, Pending), (2, Pending), (3, Pending)]);
+        let states: Vec<_> = second.intervals.iter().map(|interval| interval.state).collect();
+        assert_eq!(states, vec![AlreadyRunning, AlreadyRunning, Pending, Pending]);
+        assert_eq!(second.id, 1);
+
+        // Other workflows don't share intervals
+        let other = create(&backfills, 1, &[(0, Pending)]);
+        assert_eq!(other.intervals[0].state, Pending);
+
+        // Nor do backfills that are over
+        backfills.set_state(first.id, 0, Success);
+        backfills.set_state(first.id, 1, Failed);
+        assert_eq!(backfills.get(first.id).unwrap().state, BackfillState::Failed);
+        let third = create(&backfills, 0, &[(0, Pending), (1, Pending)]);
+        assert!(third.intervals.iter().all(|interval| interval.state == Pending));
+    }
+
+    #[test]
+    fn a_backfill_with_nothing_to_run_is_done_straight_away() {
+        use IntervalState::*;
+        let backfill = create(&Backfills::new(), 0, &[(0, AlreadySucceeded), (1, AlreadyRunning)]);
+        assert_eq!(backfill.state, BackfillState::Success);
+        assert_eq!(backfill.end_time, Some(backfill.start_time));
+    }
+
+    #[test]
+    fn a_backfill_succeeds_once_every_interval_did() {
+        use IntervalState::*;
+        let backfills = Backfills::new();
+        let backfill = create(&backfills, 0, &[(0, Pending), (1, Pending)]);
+        for interval in 0..2 {
+            assert!(backfills.start_interval(backfill.id, interval));
+            assert!(!backfills.start_interval(backfill.id, interval));
+        }
+        backfills.set_state(backfill.id, 0, Success);
+        assert_eq!(backfills.get(backfill.id).unwrap().state, BackfillState::Running);
+        let progress = backfills.set_state(backfill.id, 1, Success);
+        assert_eq!(progress.percent_done, 100.0);
+        let backfill = backfills.get(backfill.id).unwrap();
+        assert_eq!(backfill.state, BackfillState::Success);
+        assert!(backfill.end_time.is_some());
+    }
+
+    #[test]
+    fn cancelling_stops_pending_intervals_and_returns_the_running_runs() {
+        use IntervalState::*;
+        let backfills = Backfills::new();
+        let backfill = create(&backfills, 0, &[(0, Pending), (1, Pending), (2, Pending)]);
+        assert!(backfills.start_interval(backfill.id, 0));
+        assert!(backfills.start_interval(backfill.id, 1));
+        backfills.set_state(backfill.id, 0, Success);
+
+        assert_eq!(backfills.cancel(backfill.id), Some(vec!["backfill__2024-01-02".to_string()]));
+        let cancelled = backfills.get(backfill.id).unwrap();
+        assert_eq!(cancelled.intervals[2].state, Cancelled);
+        assert_eq!(cancelled.state, BackfillState::Running);
+        assert!(!backfills.start_interval(backfill.id, 2));
+
+        // Once the cancelled run is over, so is the backfill
+        backfills.set_state(backfill.id, 1, Cancelled);
+        assert_eq!(backfills.get(backfill.id).unwrap().state, BackfillState::Cancelled);
+        assert_eq!(backfills.cancel(backfill.id), Some(Vec::new()));
+        assert_eq!(backfills.cancel(7), None);
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Default)]
+struct Tokens {
+    /// Tokens of the runs going on, keyed by `(workflow_id, run_id)`.
+    runs: HashMap<(usize, String), CancellationToken>,
+    /// Tokens of the tasks of those runs, keyed by `(workflow_id, run_id, task_id)`.
+    tasks: HashMap<(usize, String, usize), CancellationToken>,
+}
+
+/// Cancellation signals shared between the HTTP API and the scheduler.
+///
+/// Every run of a workflow has its own token, and each task's token is a child of its run's
+/// token, so cancelling a run cancels every one of its tasks but leaves other runs of the
+/// same workflow alone.
+#[derive(Clone, Default)]
+pub struct Cancellations {
+    tokens: Arc<Mutex<Tokens>>,
+}
+
+impl Cancellations {
+    /// Creates an empty set of cancellation tokens.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the token of a run, making it one that can be cancelled until `finish` is called.
+    pub fn start(&self, workflow_id: usize, run_id: &str) -> CancellationToken {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.runs.entry((workflow_id, run_id.to_string())).or_default().clone()
+    }
+
+    /// Returns the token of a task of a run, creating it if needed.
+    pub fn task(&self, workflow_id: usize, run_id: &str, task_id: usize) -> CancellationToken {
+        let mut tokens = self.tokens.lock().unwrap();
+        let run = tokens.runs.entry((workflow_id, run_id.to_string())).or_default().clone();
+        tokens
+            .tasks
+            .entry((workflow_id, run_id.to_string(), task_id))
+            .or_insert_with(|| run.child_token())
+            .clone()
+    }
+
+    /// Cancels a run and all of its tasks. Returns `false` if the run is not going on.
+    pub fn cancel_run(&self, workflow_id: usize, run_id: &str) -> bool {
+        let tokens = self.tokens.lock().unwrap();
+        match tokens.runs.get(&(workflow_id, run_id.to_string())) {
+            Some(run) => {
+                run.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancels a single task of a run, whether it is running or still waiting to run. Returns
+    /// `false` if the run is not going on.
+    pub fn cancel_task(&self, workflow_id: usize, run_id: &str, task_id: usize) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        let Some(run) = tokens.runs.get(&(workflow_id, run_id.to_string())).cloned() else {
+            return false;
+        };
+        tokens
+            .tasks
+            .entry((workflow_id, run_id.to_string(), task_id))
+            .or_insert_with(|| run.child_token())
+            .cancel();
+        true
+    }
+
+    /// Forgets the tokens of a run that is over.
+    pub fn finish(&self, workflow_id: usize, run_id: &str) {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.runs.remove(&(workflow_id, run_id.to_string()));
+        tokens.tasks.retain(|(id, run, _), _| *id != workflow_id || run != run_id);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::backfill::{BackfillRequest, BackfillState};
+use reqwest::{Client, Url};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// How often a backfill's progress is looked at.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+const BACKFILL_USAGE: &str = "Usage: thermidor backfill <workflow> --start <date> --end <date> \
+[--max-active-runs <n>] [--rerun-successful]
+
+Runs <workflow>, given by id or name, for each of its schedule intervals between the two dates
+(YYYY-MM-DD or RFC 3339) on the Thermidor server at $THERMIDOR_URL (http://localhost:3000 by
+default), and reports progress until every run is done.";
+
+/// Runs the command given on the command line, if any, and returns the code to exit with.
+/// Returns `None` without a command, in which case the server is started.
+pub async fn run(args: &[String]) -> Option<i32> {
+    let (command, args) = args.split_first()?;
+    let code = match command.as_str() {
+        "backfill" => match parse_backfill(args) {
+            Ok((workflow, request)) => match backfill(&workflow, &request).await {
+                Ok(true) => 0,
+                Ok(false) => 1,
+                Err(err) => {
+                    eprintln!("Backfill failed: {}", err);
+                    1
+                }
+            },
+            Err(err) => {
+                eprintln!("{}\n\n{}", err, BACKFILL_USAGE);
+                2
+            }
+        },
+        "help" | "--help" | "-h" => {
+            println!("Usage: thermidor [backfill ...]\n\nStarts the server without a command.\n\n{}", BACKFILL_USAGE);
+            0
+        }
+        other => {
+            eprintln!("Unknown command '{}'. Run `thermidor help` for usage.", other);
+            2
+        }
+    };
+    Some(code)
+}
+
+/// Parses the arguments of the `backfill` command into the workflow and what to backfill.
+fn parse_backfill(args: &[String]) -> Result<(String, BackfillRequest), String> {
+    let mut workflow = None;
+    let mut start = None;
+    let mut end = None;
+    let mut max_active_runs = 1;
+    let mut rerun_successful = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| args.next().cloned().ok_or_else(|| format!("Missing value for {}", flag));
+        match arg.as_str() {
+            "--start" => start = Some(value(arg)?),
+            "--end" => end = Some(value(arg)?),
+            "--max-active-runs" => {
+                let max = value(arg)?;
+                max_active_runs = max
+                    .parse()
+                    .ok()
+                    .filter(|max| *max > 0)
+                    .ok_or_else(|| format!("Invalid number of runs for --max-active-runs: {}", max))?;
+            }
+            "--rerun-successful" => rerun_successful = true,
+            flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+            name if workflow.is_none() => workflow = Some(name.to_string()),
+            extra => return Err(format!("Unexpected argument '{}'", extra)),
+        }
+    }
+
+    let request = BackfillRequest {
+        start: start.ok_or("Missing --start")?,
+        end: end.ok_or("Missing --end")?,
+        max_active_runs,
+        rerun_successful,
+    };
+    Ok((workflow.ok_or("Missing workflow")?, request))
+}
+
+/// Starts a backfill on the server and prints its progress until it is done. Returns whether
+/// every interval succeeded.
+async fn backfill(workflow: &str, request: &BackfillRequest) -> Result<bool, String> {
+    let server = std::env::var("THERMIDOR_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    let client = Client::new();
+
+    let response = client
+        .post(backfills_url(&server, workflow, None)?)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(request).map_err(|err| err.to_string())?)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut backfill = read_json(response).await?;
+    let id = backfill["id"].clone();
+    println!(
+        "Backfill {} of workflow '{}': {} intervals, {} already succeeded, {} already running",
+        id,
+        backfill["workflow"].as_str().unwrap_or(workflow),
+        backfill["progress"]["total"],
+        backfill["progress"]["already_succeeded"],
+        backfill["progress"]["already_running"],
+    );
+
+    let mut last_progress = Value::Null;
+    loop {
+        let progress = &backfill["progress"];
+        if *progress != last_progress {
+            println!(
+                "{:>5.1}% done: {} succeeded, {} failed, {} cancelled, {} running, {} pending",
+                progress["percent_done"].as_f64().unwrap_or_default(),
+                progress["success"],
+                progress["failed"],
+                progress["cancelled"],
+                progress["running"],
+                progress["pending"],
+            );
+            last_progress = progress.clone();
+        }
+
+        let state: Option<BackfillState> = serde_json::from_value(backfill["state"].clone()).ok();
+        match state {
+            Some(BackfillState::Running) => {}
+            Some(state) => {
+                println!("Backfill {} finished: {}", id, state);
+                return Ok(state == BackfillState::Success);
+            }
+            None => return Err(format!("Unexpected response: {}", backfill)),
+        }
+
+        sleep(POLL_INTERVAL).await;
+        let response = client
+            .get(backfills_url(&server, workflow, Some(&id.to_string()))?)
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        backfill = read_json(response).await?;
+    }
+}
+
+/// Builds the URL of the backfills of a workflow on the server, or of the backfill `id` of it.
+/// The workflow's name is escaped, so it always stays a single path segment.
+fn backfills_url(server: &str, workflow: &str, id: Option<&str>) -> Result<Url, String> {
+    let mut url = Url::parse(server).map_err(|err| format!("Invalid server URL '{}': {}", server, err))?;
+    {
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| format!("Invalid server URL '{}'", server))?;
+        segments.pop_if_empty().push("workflow").push(workflow).push("backfills");
+        if let Some(id) = id {
+            segments.push(id);
+        }
+    }
+    Ok(url)
+}
+
+/// Reads the JSON body of a response, turning error responses into their message.
+async fn read_json(response: reqwest::Response) -> Result<Value, String> {
+    let status = response.status();
+    let body = response.text().await.map_err(|err| err.to_string())?;
+    let json: Value = serde_json::from_str(&body).map_err(|_| format!("{}: {}", status, body))?;
+    if !status.is_success() {
+        return Err(json["error"].as_str().map(str::to_string).unwrap_or(body));
+    }
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workflow_names_stay_a_single_path_segment() {
+        let url = backfills_url("http://localhost:3000/", "daily/../orders?x", None).unwrap();
+        assert_eq!(url.as_str(), "http://localhost:3000/workflow/daily%2F..%2Forders%3Fx/backfills");
+        let url = backfills_url("http://localhost:3000/thermidor", "daily", Some("3")).unwrap();
+        assert_eq!(url.as_str(), "http://localhost:3000/thermidor/workflow/daily/backfills/3");
+        assert!(backfills_url("localhost:3000", "daily", None).is_err());
+    }
+}
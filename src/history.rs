@@ -0,0 +1,346 @@
+use crate::run::{RunState, RunTrigger, TaskInstance, WorkflowRun};
+use chrono::{DateTime, Utc};
+use crate::operator::Outcome;
+use crate::task::{Attempt, Task};
+use crate::state::TaskState;
+use serde_json::Value;
+use sqlx::postgres::PgRow;
+use sqlx::types::Json;
+use sqlx::{PgPool, Row};
+use std::collections::HashSet;
+
+/// Keeps the history of workflow runs in Postgres, in the `history` schema.
+#[derive(Clone)]
+pub struct History {
+    pool: PgPool,
+}
+
+impl History {
+    /// Creates a history stored in the given database.
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Records every attempt of a task in a run, replacing earlier records of the same attempts.
+    pub async fn record_attempts(&self, workflow: &str, run_id: &str, task: &Task) -> Result<(), sqlx::Error> {
+        for attempt in &task.attempts {
+            sqlx::query(
+                "INSERT INTO history.task_attempts \
+                 (workflow, run_id, task_id, task_name, attempt, start_time, end_time, outcome, \
+                  exit_code, signal, failure_reason, stdout_log, stderr_log) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13) \
+                 ON CONFLICT (workflow, run_id, task_id, attempt) DO UPDATE SET \
+                 end_time = EXCLUDED.end_time, outcome = EXCLUDED.outcome, exit_code = EXCLUDED.exit_code, \
+                 signal = EXCLUDED.signal, failure_reason = EXCLUDED.failure_reason"
+            )
+            .bind(workflow)
+            .bind(run_id)
+            .bind(task.id as i32)
+            .bind(&task.name)
+            .bind(attempt.number as i32)
+            .bind(attempt.start_time)
+            .bind(attempt.end_time)
+            .bind(attempt.outcome.as_ref().map(Json))
+            .bind(attempt.exit_code)
+            .bind(attempt.signal)
+            .bind(&attempt.failure_reason)
+            .bind(attempt.stdout_log.to_string_lossy().as_ref())
+            .bind(attempt.stderr_log.to_string_lossy().as_ref())
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Records a workflow run, replacing an earlier record of the same run.
+    pub async fn record_run(&self, run: &WorkflowRun) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO history.workflow_runs \
+             (workflow, run_id, trigger, logical_date, data_interval_start, data_interval_end, params, \
+              state, start_time, end_time) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) \
+             ON CONFLICT (workflow, run_id) DO UPDATE SET \
+             trigger = EXCLUDED.trigger, params = EXCLUDED.params, state = EXCLUDED.state, \
+             start_time = EXCLUDED.start_time, end_time = EXCLUDED.end_time"
+        )
+        .bind(&run.workflow)
+        .bind(&run.run_id)
+        .bind(run.trigger.to_string())
+        .bind(run.logical_date)
+        .bind(run.data_interval_start)
+        .bind(run.data_interval_end)
+        .bind(Json(&run.params))
+        .bind(run.state.to_string())
+        .bind(run.start_time)
+        .bind(run.end_time)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Records the state of a task in a run.
+    pub async fn record_task(&self, workflow: &str, run_id: &str, task: &Task) -> Result<(), sqlx::Error> {
+        let instance = TaskInstance::from(task);
+        sqlx::query(
+            "INSERT INTO history.task_instances \
+             (workflow, run_id, task_id, task_name, state, attempts, start_time, end_time, output) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) \
+             ON CONFLICT (workflow, run_id, task_id) DO UPDATE SET \
+             task_name = EXCLUDED.task_name, state = EXCLUDED.state, attempts = EXCLUDED.attempts, \
+             start_time = EXCLUDED.start_time, end_time = EXCLUDED.end_time, output = EXCLUDED.output"
+        )
+        .bind(workflow)
+        .bind(run_id)
+        .bind(instance.task_id as i32)
+        .bind(&instance.name)
+        .bind(instance.state.to_string())
+        .bind(instance.attempts as i32)
+        .bind(instance.start_time)
+        .bind(instance.end_time)
+        .bind(instance.output.map(Json))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the latest runs of a workflow, most recent first.
+    pub async fn runs(&self, workflow: &str, limit: i64) -> Result<Vec<WorkflowRun>, sqlx::Error> {
+        let rows = sqlx::query(
+            "SELECT * FROM history.workflow_runs WHERE workflow = $1 ORDER BY start_time DESC LIMIT $2"
+        )
+        .bind(workflow)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter().map(run_from_row).collect()
+    }
+
+    /// Returns the end of the latest data interval processed by a finished scheduled run of the
+    /// workflow, if there is one.
+    pub async fn last_scheduled_interval_end(&self, workflow: &str) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+        sqlx::query(
+            "SELECT max(data_interval_end) AS end FROM history.workflow_runs \
+             WHERE workflow = $1 AND trigger = $2 AND state NOT IN ($3, $4)"
+        )
+        .bind(workflow)
+        .bind(RunTrigger::Scheduled.to_string())
+        .bind(RunState::Queued.to_string())
+        .bind(RunState::Running.to_string())
+        .fetch_one(&self.pool)
+        .await?
+        .try_get("end")
+    }
+
+    /// Marks the runs of a workflow left queued or running by a server that stopped as failed,
+    /// along with their tasks that had not finished, and returns their ids.
+    pub async fn fail_interrupted_runs(&self, workflow: &str) -> Result<Vec<String>, sqlx::Error> {
+        let now = Utc::now();
+        let run_ids: Vec<String> = sqlx::query(
+            "UPDATE history.workflow_runs SET state = $2, end_time = $3 \
+             WHERE workflow = $1 AND state IN ($4, $5) RETURNING run_id"
+        )
+        .bind(workflow)
+        .bind(RunState::Failed.to_string())
+        .bind(now)
+        .bind(RunState::Queued.to_string())
+        .bind(RunState::Running.to_string())
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|row| row.try_get("run_id"))
+        .collect::<Result<_, _>>()?;
+
+        sqlx::query(
+            "UPDATE history.task_instances SET state = $3, end_time = $4 \
+             WHERE workflow = $1 AND run_id = ANY($2) AND state IN ($5, $6, $7)"
+        )
+        .bind(workflow)
+        .bind(&run_ids)
+        .bind(TaskState::Failure.to_string())
+        .bind(now)
+        .bind(TaskState::Queued.to_string())
+        .bind(TaskState::Running.to_string())
+        .bind(TaskState::UpForRetry.to_string())
+        .execute(&self.pool)
+        .await?;
+        Ok(run_ids)
+    }
+
+    /// Ties an idempotency key to the run `run_id` of the workflow, unless the key was already
+    /// used. Returns the id of the run the key belongs to, which is `run_id` if it is new.
+    pub async fn claim_idempotency_key(&self, workflow: &str, key: &str, run_id: &str) -> Result<String, sqlx::Error> {
+        let claimed = sqlx::query(
+            "INSERT INTO history.idempotency_keys (workflow, key, run_id) VALUES ($1, $2, $3) \
+             ON CONFLICT (workflow, key) DO NOTHING"
+        )
+        .bind(workflow)
+        .bind(key)
+        .bind(run_id)
+        .execute(&self.pool)
+        .await?;
+        if claimed.rows_affected() == 1 {
+            return Ok(run_id.to_string());
+        }
+        let claimed_by = self.idempotency_key_run(workflow, key).await?;
+        Ok(claimed_by.unwrap_or_else(|| run_id.to_string()))
+    }
+
+    /// Returns the id of the run an idempotency key of the workflow belongs to, if it was used.
+    pub async fn idempotency_key_run(&self, workflow: &str, key: &str) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query("SELECT run_id FROM history.idempotency_keys WHERE workflow = $1 AND key = $2")
+            .bind(workflow)
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|row| row.try_get("run_id"))
+            .transpose()
+    }
+
+    /// Returns the logical dates between `start` and `end` for which a run of the workflow
+    /// succeeded, whatever started it.
+    pub async fn succeeded_dates(
+        &self,
+        workflow: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<HashSet<DateTime<Utc>>, sqlx::Error> {
+        self.dates_in_states(workflow, &[RunState::Success], start, end).await
+    }
+
+    /// Returns the logical dates between `start` and `end` for which a run of the workflow is
+    /// queued or running, whatever started it.
+    pub async fn active_dates(
+        &self,
+        workflow: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<HashSet<DateTime<Utc>>, sqlx::Error> {
+        self.dates_in_states(workflow, &[RunState::Queued, RunState::Running], start, end).await
+    }
+
+    async fn dates_in_states(
+        &self,
+        workflow: &str,
+        states: &[RunState],
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<HashSet<DateTime<Utc>>, sqlx::Error> {
+        let states: Vec<_> = states.iter().map(RunState::to_string).collect();
+        let rows = sqlx::query(
+            "SELECT DISTINCT logical_date FROM history.workflow_runs \
+             WHERE workflow = $1 AND state = ANY($2) AND logical_date BETWEEN $3 AND $4"
+        )
+        .bind(workflow)
+        .bind(states)
+        .bind(start)
+        .bind(end)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter().map(|row| row.try_get("logical_date")).collect()
+    }
+
+    /// Returns the ids of the runs of a workflow that start with `prefix`.
+    pub async fn run_ids_starting_with(&self, workflow: &str, prefix: &str) -> Result<HashSet<String>, sqlx::Error> {
+        let rows = sqlx::query(
+            "SELECT run_id FROM history.workflow_runs WHERE workflow = $1 AND left(run_id, length($2)) = $2"
+        )
+        .bind(workflow)
+        .bind(prefix)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter().map(|row| row.try_get("run_id")).collect()
+    }
+
+    /// Returns a run of a workflow with the states of its tasks, if there is such a run.
+    pub async fn run(&self, workflow: &str, run_id: &str) -> Result<Option<(WorkflowRun, Vec<TaskInstance>)>, sqlx::Error> {
+        let Some(row) = sqlx::query("SELECT * FROM history.workflow_runs WHERE workflow = $1 AND run_id = $2")
+            .bind(workflow)
+            .bind(run_id)
+            .fetch_optional(&self.pool)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let run = run_from_row(&row)?;
+
+        let rows = sqlx::query(
+            "SELECT * FROM history.task_instances WHERE workflow = $1 AND run_id = $2 ORDER BY task_id"
+        )
+        .bind(workflow)
+        .bind(run_id)
+        .fetch_all(&self.pool)
+        .await?;
+        let tasks = rows
+            .iter()
+            .map(|row| {
+                let task_id: i32 = row.try_get("task_id")?;
+                let attempts: i32 = row.try_get("attempts")?;
+                let output: Option<Json<Value>> = row.try_get("output")?;
+                Ok(TaskInstance {
+                    task_id: task_id as usize,
+                    name: row.try_get("task_name")?,
+                    state: parse(row.try_get("state")?)?,
+                    attempts: attempts as usize,
+                    start_time: row.try_get("start_time")?,
+                    end_time: row.try_get("end_time")?,
+                    output: output.map(|output| output.0),
+                })
+            })
+            .collect::<Result<_, sqlx::Error>>()?;
+        Ok(Some((run, tasks)))
+    }
+
+    /// Returns every attempt of every task in a run, with the id of its task, in the order
+    /// they were made.
+    pub async fn attempts(&self, workflow: &str, run_id: &str) -> Result<Vec<(usize, Attempt)>, sqlx::Error> {
+        let rows = sqlx::query(
+            "SELECT * FROM history.task_attempts WHERE workflow = $1 AND run_id = $2 ORDER BY task_id, attempt"
+        )
+        .bind(workflow)
+        .bind(run_id)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter()
+            .map(|row| {
+                let task_id: i32 = row.try_get("task_id")?;
+                let number: i32 = row.try_get("attempt")?;
+                let outcome: Option<Json<Outcome>> = row.try_get("outcome")?;
+                let stdout_log: String = row.try_get("stdout_log")?;
+                let stderr_log: String = row.try_get("stderr_log")?;
+                let attempt = Attempt {
+                    number: number as usize,
+                    start_time: row.try_get("start_time")?,
+                    end_time: row.try_get("end_time")?,
+                    outcome: outcome.map(|outcome| outcome.0),
+                    exit_code: row.try_get("exit_code")?,
+                    signal: row.try_get("signal")?,
+                    failure_reason: row.try_get("failure_reason")?,
+                    stdout_log: stdout_log.into(),
+                    stderr_log: stderr_log.into(),
+                };
+                Ok((task_id as usize, attempt))
+            })
+            .collect()
+    }
+}
+
+fn run_from_row(row: &PgRow) -> Result<WorkflowRun, sqlx::Error> {
+    let params: Json<serde_json::Map<String, Value>> = row.try_get("params")?;
+    Ok(WorkflowRun {
+        run_id: row.try_get("run_id")?,
+        workflow: row.try_get("workflow")?,
+        trigger: parse(row.try_get("trigger")?)?,
+        logical_date: row.try_get("logical_date")?,
+        data_interval_start: row.try_get("data_interval_start")?,
+        data_interval_end: row.try_get("data_interval_end")?,
+        params: params.0,
+        state: parse(row.try_get("state")?)?,
+        start_time: row.try_get("start_time")?,
+        end_time: row.try_get("end_time")?,
+    })
+}
+
+/// Parses a value stored as text, such as a state.
+fn parse<T: std::str::FromStr<Err = String>>(value: String) -> Result<T, sqlx::Error> {
+    value.parse().map_err(|err: String| sqlx::Error::Decode(err.into()))
+}
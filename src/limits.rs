@@ -0,0 +1,251 @@
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// A kind of resource a task's process can be limited in.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Resource {
+    Memory,
+    CpuTime,
+    OpenFiles,
+    Processes,
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let resource = match self {
+            Resource::Memory => "memory",
+            Resource::CpuTime => "cpu_time",
+            Resource::OpenFiles => "open_files",
+            Resource::Processes => "processes",
+        };
+        write!(f, "{}", resource)
+    }
+}
+
+/// Operating system limits applied to a task's process with `setrlimit` before it is exec'd.
+/// Processes it starts inherit them. Unset limits are left as the server's.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// Maximum size of the address space in bytes (`RLIMIT_AS`).
+    pub max_memory_bytes: Option<u64>,
+    /// CPU time in seconds after which the process receives `SIGXCPU` (`RLIMIT_CPU`).
+    /// It is killed if it is still running a second later.
+    pub max_cpu_seconds: Option<u64>,
+    /// Maximum number of open file descriptors (`RLIMIT_NOFILE`).
+    pub max_open_files: Option<u64>,
+    /// Maximum number of processes of the user the server runs as, including those
+    /// outside the task (`RLIMIT_NPROC`).
+    pub max_processes: Option<u64>,
+}
+
+/// The type `setrlimit` takes the resource as, which glibc declares differently from other libcs.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type RlimitResource = libc::c_int;
+
+/// How far the CPU time a process is reported to have used can trail the time the kernel
+/// checks its limit against, as the former is accounted in scheduler ticks.
+const CPU_TIME_ACCOUNTING_SLACK: Duration = Duration::from_millis(100);
+
+/// Messages printed by common runtimes when a limit stops them from getting a resource.
+const MEMORY_MARKERS: &[&str] = &[
+    "Cannot allocate memory",
+    "out of memory",
+    "Out of memory",
+    "MemoryError",
+    "std::bad_alloc",
+    "memory allocation of",
+];
+const OPEN_FILES_MARKERS: &[&str] = &["Too many open files"];
+const PROCESSES_MARKERS: &[&str] = &["Resource temporarily unavailable", "retry: No child processes", "Cannot fork"];
+
+impl ResourceLimits {
+    /// Returns whether no limit is set.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Applies the limits to the current process.
+    ///
+    /// This runs in the forked child before exec, so it must only make async-signal-safe calls.
+    pub fn apply(&self) -> io::Result<()> {
+        fn set(resource: RlimitResource, soft: u64, hard: u64) -> io::Result<()> {
+            let limit = libc::rlimit { rlim_cur: soft as libc::rlim_t, rlim_max: hard as libc::rlim_t };
+            // SAFETY: setrlimit only reads the struct passed to it.
+            if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        if let Some(bytes) = self.max_memory_bytes {
+            set(libc::RLIMIT_AS, bytes, bytes)?;
+        }
+        if let Some(seconds) = self.max_cpu_seconds {
+            // The soft limit sends SIGXCPU, which tells the violation apart from other kills
+            set(libc::RLIMIT_CPU, seconds, Self::cpu_hard_limit(seconds))?;
+        }
+        if let Some(files) = self.max_open_files {
+            set(libc::RLIMIT_NOFILE, files, files)?;
+        }
+        if let Some(processes) = self.max_processes {
+            set(libc::RLIMIT_NPROC, processes, processes)?;
+        }
+        Ok(())
+    }
+
+    /// The CPU time in seconds at which a process limited to `seconds` is killed.
+    fn cpu_hard_limit(seconds: u64) -> u64 {
+        seconds.saturating_add(1)
+    }
+
+    /// Works out whether a process that exited unsuccessfully did so because it ran into one
+    /// of the limits, from how it exited, the CPU time it used or the error messages it printed
+    /// to stderr.
+    ///
+    /// Only limits that are set are considered. Running out of CPU time ends the process with
+    /// `SIGXCPU`; a shell running it as a child exits with 128 plus the signal number instead.
+    /// A process that ignores `SIGXCPU` is killed with `SIGKILL` at the hard limit, which only
+    /// counts if `cpu_time` shows it got there, as anyone may send `SIGKILL`. Running out of
+    /// memory or file descriptors makes system calls fail instead of killing the process, so
+    /// those are recognised by their message.
+    pub fn violated(
+        &self,
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+        cpu_time: Option<Duration>,
+        stderr: &str,
+    ) -> Option<Resource> {
+        let printed = |markers: &[&str]| markers.iter().any(|marker| stderr.contains(marker));
+        let ended_by = |sig: i32| signal == Some(sig) || exit_code == Some(128 + sig);
+        let reached_hard_limit = |seconds: u64| {
+            let hard_limit = Duration::from_secs(Self::cpu_hard_limit(seconds));
+            cpu_time.is_some_and(|time| time + CPU_TIME_ACCOUNTING_SLACK >= hard_limit)
+        };
+
+        let out_of_cpu_time = self.max_cpu_seconds.is_some_and(|seconds| {
+            ended_by(libc::SIGXCPU) || (ended_by(libc::SIGKILL) && reached_hard_limit(seconds))
+        });
+        if out_of_cpu_time {
+            Some(Resource::CpuTime)
+        } else if self.max_memory_bytes.is_some() && printed(MEMORY_MARKERS) {
+            Some(Resource::Memory)
+        } else if self.max_open_files.is_some() && printed(OPEN_FILES_MARKERS) {
+            Some(Resource::OpenFiles)
+        } else if self.max_processes.is_some() && printed(PROCESSES_MARKERS) {
+            Some(Resource::Processes)
+        } else {
+            None
+        }
+    }
+}
+
+/// Waits until the process `pid` has exited without reaping it, so whoever owns it can still
+/// wait for it, and returns the CPU time used by it and the children it waited for.
+///
+/// This blocks the calling thread. It is only supported on Linux, and returns `None` elsewhere.
+#[cfg(target_os = "linux")]
+pub fn cpu_time_at_exit(pid: u32) -> Option<Duration> {
+    // SAFETY: both structs are plain data for which all zeroes is a valid value.
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // The libc wrapper of waitid doesn't pass on the rusage the system call can fill in.
+        // SAFETY: the pointers are valid for writes for the duration of the call.
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_waitid,
+                libc::P_PID,
+                pid as libc::id_t,
+                &mut info as *mut libc::siginfo_t,
+                libc::WEXITED | libc::WNOWAIT,
+                &mut usage as *mut libc::rusage,
+            )
+        };
+        if result == 0 {
+            break;
+        }
+        if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            return None;
+        }
+    }
+
+    let time = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    Some(time(usage.ru_utime) + time(usage.ru_stime))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn cpu_time_at_exit(_pid: u32) -> Option<Duration> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit_cpu() -> ResourceLimits {
+        ResourceLimits { max_cpu_seconds: Some(1), ..ResourceLimits::default() }
+    }
+
+    #[test]
+    fn sigxcpu_means_the_cpu_time_ran_out() {
+        let limits = limit_cpu();
+        assert_eq!(limits.violated(None, Some(libc::SIGXCPU), None, ""), Some(Resource::CpuTime));
+        assert_eq!(limits.violated(Some(128 + libc::SIGXCPU), None, None, ""), Some(Resource::CpuTime));
+        assert_eq!(limits.violated(Some(1), None, None, ""), None);
+        // Not without a CPU time limit
+        assert_eq!(ResourceLimits::default().violated(None, Some(libc::SIGXCPU), None, ""), None);
+        assert_eq!(ResourceLimits::default().violated(Some(128 + libc::SIGXCPU), None, None, ""), None);
+    }
+
+    #[test]
+    fn sigkill_only_counts_once_the_hard_limit_is_reached() {
+        let limits = limit_cpu();
+        let killed = [(None, Some(libc::SIGKILL)), (Some(128 + libc::SIGKILL), None)];
+        for (exit_code, signal) in killed {
+            assert_eq!(limits.violated(exit_code, signal, None, ""), None);
+            assert_eq!(limits.violated(exit_code, signal, Some(Duration::from_millis(1500)), ""), None);
+            assert_eq!(limits.violated(exit_code, signal, Some(Duration::from_secs(2)), ""), Some(Resource::CpuTime));
+            // Reported CPU time can fall a little short of the limit it was killed at
+            assert_eq!(limits.violated(exit_code, signal, Some(Duration::from_millis(1994)), ""), Some(Resource::CpuTime));
+            // Without a CPU time limit, SIGKILL came from somewhere else
+            let unlimited = ResourceLimits::default();
+            assert_eq!(unlimited.violated(exit_code, signal, Some(Duration::from_secs(60)), ""), None);
+        }
+    }
+
+    #[test]
+    fn limits_are_recognised_from_the_messages_on_stderr() {
+        let cases = [
+            (ResourceLimits { max_memory_bytes: Some(1 << 20), ..ResourceLimits::default() }, MEMORY_MARKERS, Resource::Memory),
+            (ResourceLimits { max_open_files: Some(16), ..ResourceLimits::default() }, OPEN_FILES_MARKERS, Resource::OpenFiles),
+            (ResourceLimits { max_processes: Some(4), ..ResourceLimits::default() }, PROCESSES_MARKERS, Resource::Processes),
+        ];
+        for (limits, markers, resource) in cases {
+            for marker in markers {
+                let stderr = format!("Traceback (most recent call last):\nerror: {}\n", marker);
+                assert_eq!(limits.violated(Some(1), None, None, &stderr), Some(resource), "{}", marker);
+                // Only limits that are set are considered
+                assert_eq!(ResourceLimits::default().violated(Some(1), None, None, &stderr), None, "{}", marker);
+            }
+            assert_eq!(limits.violated(Some(1), None, None, "error: file not found\n"), None);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn cpu_time_at_exit_leaves_the_process_to_be_reaped() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let mut child = std::process::Command::new("sh").args(["-c", "exit 3"]).spawn().unwrap();
+        assert!(cpu_time_at_exit(child.id()).is_some());
+        let status = child.wait().unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(status.signal(), None);
+    }
+}
@@ -0,0 +1,273 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::sync::broadcast;
+
+/// Number of lines a live subscriber may fall behind before it starts missing lines.
+const LIVE_CHANNEL_CAPACITY: usize = 1024;
+
+/// One of the two output streams captured from a task's process.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStream {
+    #[default]
+    Stdout,
+    Stderr,
+}
+
+impl fmt::Display for LogStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stream = match self {
+            LogStream::Stdout => "stdout",
+            LogStream::Stderr => "stderr",
+        };
+        write!(f, "{}", stream)
+    }
+}
+
+/// A single line of output from a running task, as sent to live subscribers.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogLine {
+    pub attempt: usize,
+    pub stream: LogStream,
+    pub line: String,
+}
+
+/// Appends one stream of an attempt to its log file and forwards each line to live subscribers.
+pub struct LogWriter {
+    file: File,
+    attempt: usize,
+    stream: LogStream,
+    live: broadcast::Sender<LogLine>,
+}
+
+impl LogWriter {
+    /// Writes a chunk of output (normally one line) to the log file and to subscribers.
+    pub async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes).await?;
+
+        let line = String::from_utf8_lossy(bytes);
+        // Sending only fails when nobody is listening, which is fine.
+        let _ = self.live.send(LogLine {
+            attempt: self.attempt,
+            stream: self.stream,
+            line: line.trim_end_matches(['\n', '\r']).to_string(),
+        });
+        Ok(())
+    }
+
+    /// Copies everything from `reader` into the log, line by line, until it is closed,
+    /// passing every line to `inspect` as well.
+    pub async fn pump<R, F>(&mut self, reader: R, mut inspect: F) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        F: FnMut(&[u8]),
+    {
+        let mut reader = BufReader::new(reader);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line).await? == 0 {
+                break;
+            }
+            inspect(&line);
+            self.write(&line).await?;
+        }
+        self.file.flush().await
+    }
+}
+
+/// The part of a log file to return. `tail` takes precedence over `offset`/`length`.
+#[derive(Debug, Clone, Default)]
+pub struct LogRange {
+    pub offset: Option<u64>,
+    pub length: Option<u64>,
+    pub tail: Option<u64>,
+}
+
+/// A slice of a stored log file.
+#[derive(Debug)]
+pub struct LogChunk {
+    /// Total size of the log file in bytes.
+    pub size: u64,
+    /// Byte offset of `content` within the file.
+    pub offset: u64,
+    pub content: String,
+}
+
+/// Live output channels of running tasks, keyed by `(workflow_id, run_id, task_id)`.
+type LiveSenders = HashMap<(usize, String, usize), broadcast::Sender<LogLine>>;
+
+/// Stores the stdout/stderr of every task attempt on the filesystem, laid out as
+/// `<root>/workflow_<id>/<run_id>/task_<id>/attempt_<n>/<stream>.log`, and fans out the output
+/// of running tasks to live subscribers. Runs going on at the same time keep their output apart.
+#[derive(Debug, Clone)]
+pub struct LogStore {
+    root: PathBuf,
+    live: Arc<Mutex<LiveSenders>>,
+}
+
+/// Returns whether `run_id` can name a directory of the store. Run ids given by API clients
+/// are checked with it, so none of them leads out of the store's root.
+pub fn is_valid_run_id(run_id: &str) -> bool {
+    !run_id.is_empty() && run_id != "." && !run_id.contains("..") && !run_id.contains(['/', '\\', '\0'])
+}
+
+impl LogStore {
+    /// Creates a log store rooted at the given directory.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            live: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn task_dir(&self, workflow_id: usize, run_id: &str, task_id: usize) -> PathBuf {
+        self.root
+            .join(format!("workflow_{}", workflow_id))
+            .join(run_id)
+            .join(format!("task_{}", task_id))
+    }
+
+    fn attempt_dir(&self, workflow_id: usize, run_id: &str, task_id: usize, attempt: usize) -> PathBuf {
+        self.task_dir(workflow_id, run_id, task_id).join(format!("attempt_{}", attempt))
+    }
+
+    /// Returns the path of the log file for one stream of an attempt.
+    pub fn log_path(
+        &self,
+        workflow_id: usize,
+        run_id: &str,
+        task_id: usize,
+        attempt: usize,
+        stream: LogStream,
+    ) -> PathBuf {
+        self.attempt_dir(workflow_id, run_id, task_id, attempt).join(format!("{}.log", stream))
+    }
+
+    /// Returns the path a task can write the output of an attempt to.
+    pub fn output_path(&self, workflow_id: usize, run_id: &str, task_id: usize, attempt: usize) -> PathBuf {
+        self.attempt_dir(workflow_id, run_id, task_id, attempt).join("output.json")
+    }
+
+    /// Creates the stdout and stderr log files of an attempt, replacing any previous logs for it,
+    /// and marks the task as live so its output can be subscribed to.
+    pub async fn open_attempt(
+        &self,
+        workflow_id: usize,
+        run_id: &str,
+        task_id: usize,
+        attempt: usize,
+    ) -> io::Result<(LogWriter, LogWriter)> {
+        fs::create_dir_all(self.attempt_dir(workflow_id, run_id, task_id, attempt)).await?;
+
+        let live = self
+            .live
+            .lock()
+            .unwrap()
+            .entry((workflow_id, run_id.to_string(), task_id))
+            .or_insert_with(|| broadcast::channel(LIVE_CHANNEL_CAPACITY).0)
+            .clone();
+
+        let stdout = LogWriter {
+            file: File::create(self.log_path(workflow_id, run_id, task_id, attempt, LogStream::Stdout)).await?,
+            attempt,
+            stream: LogStream::Stdout,
+            live: live.clone(),
+        };
+        let stderr = LogWriter {
+            file: File::create(self.log_path(workflow_id, run_id, task_id, attempt, LogStream::Stderr)).await?,
+            attempt,
+            stream: LogStream::Stderr,
+            live,
+        };
+        Ok((stdout, stderr))
+    }
+
+    /// Marks the task as no longer running in the run. Subscribers are disconnected once the
+    /// remaining writers of its last attempt are dropped.
+    pub fn close_live(&self, workflow_id: usize, run_id: &str, task_id: usize) {
+        self.live.lock().unwrap().remove(&(workflow_id, run_id.to_string(), task_id));
+    }
+
+    /// Subscribes to the output of a task running in the run, or returns `None` if it is not
+    /// running.
+    pub fn subscribe(&self, workflow_id: usize, run_id: &str, task_id: usize) -> Option<broadcast::Receiver<LogLine>> {
+        self.live
+            .lock()
+            .unwrap()
+            .get(&(workflow_id, run_id.to_string(), task_id))
+            .map(|sender| sender.subscribe())
+    }
+
+    /// Returns the highest attempt number of the task in the run with stored logs, if any.
+    pub async fn latest_attempt(&self, workflow_id: usize, run_id: &str, task_id: usize) -> io::Result<Option<usize>> {
+        let mut entries = match fs::read_dir(self.task_dir(workflow_id, run_id, task_id)).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let mut latest = None;
+        while let Some(entry) = entries.next_entry().await? {
+            let attempt = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("attempt_"))
+                .and_then(|n| n.parse::<usize>().ok());
+            latest = latest.max(attempt);
+        }
+        Ok(latest)
+    }
+
+    /// Reads the requested range of an attempt's log.
+    pub async fn read(
+        &self,
+        workflow_id: usize,
+        run_id: &str,
+        task_id: usize,
+        attempt: usize,
+        stream: LogStream,
+        range: &LogRange,
+    ) -> io::Result<LogChunk> {
+        let mut file = File::open(self.log_path(workflow_id, run_id, task_id, attempt, stream)).await?;
+        let size = file.metadata().await?.len();
+
+        let (offset, length) = match range.tail {
+            Some(tail) => (size.saturating_sub(tail), tail),
+            None => {
+                let offset = range.offset.unwrap_or(0).min(size);
+                (offset, range.length.unwrap_or(size - offset))
+            }
+        };
+
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut buffer = Vec::new();
+        file.take(length).read_to_end(&mut buffer).await?;
+
+        Ok(LogChunk {
+            size,
+            offset,
+            content: String::from_utf8_lossy(&buffer).into_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_ids_leading_out_of_the_store_are_invalid() {
+        assert!(is_valid_run_id("manual__2024-03-01T09:30:00.123"));
+        assert!(is_valid_run_id("backfill__2024-03-01T00:00:00__2"));
+        for run_id in ["", ".", "..", "../../..", "a/b", "..\\secret", "/etc", "run\0"] {
+            assert!(!is_valid_run_id(run_id), "{:?}", run_id);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use crate::logs::LogWriter;
+use crate::limits::Resource;
+use crate::task::Task;
+use async_trait::async_trait;
+use serde::{Serialize, Deserialize};
+use sqlx::PgPool;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+pub mod http;
+pub mod shell;
+pub mod sql;
+
+/// How a single attempt of a task ended.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "outcome")]
+pub enum Outcome {
+    Succeeded,
+    /// The process exited unsuccessfully, with a non-zero code or because of a signal.
+    Failed { exit_code: Option<i32>, signal: Option<i32> },
+    /// The process exited unsuccessfully after running into one of the task's resource limits.
+    LimitExceeded { resource: Resource, exit_code: Option<i32>, signal: Option<i32> },
+    /// The attempt ran past the task's timeout and was stopped.
+    TimedOut,
+    /// The task was cancelled while the attempt was running.
+    Cancelled,
+    /// The attempt could not be run, e.g. because the program is missing or the task's
+    /// configuration is invalid. `retryable` tells whether trying again could help.
+    Error { message: String, retryable: bool },
+    /// A SQL statement was rejected by the database. `sqlstate` is the Postgres error code.
+    QueryFailed { sqlstate: Option<String>, message: String, retryable: bool },
+    /// An HTTP request got a response with an unexpected status, or whose body failed the
+    /// task's assertion.
+    UnexpectedResponse { status: u16, message: String, retryable: bool },
+}
+
+impl Outcome {
+    /// Returns the exit code of the process, if the attempt ran one that exited normally.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            Outcome::Failed { exit_code, .. } | Outcome::LimitExceeded { exit_code, .. } => *exit_code,
+            Outcome::Succeeded => Some(0),
+            _ => None,
+        }
+    }
+
+    /// Returns the signal that killed the process, if any.
+    pub fn signal(&self) -> Option<i32> {
+        match self {
+            Outcome::Failed { signal, .. } | Outcome::LimitExceeded { signal, .. } => *signal,
+            _ => None,
+        }
+    }
+
+    /// Describes why the attempt failed, or returns `None` if it succeeded.
+    pub fn failure_reason(&self) -> Option<String> {
+        let reason = match self {
+            Outcome::Succeeded => return None,
+            Outcome::Failed { exit_code: Some(code), .. } => format!("Exited with code {}", code),
+            Outcome::Failed { signal, .. } => format!("Killed by signal {}", signal.unwrap_or_default()),
+            Outcome::LimitExceeded { resource, .. } => format!("Exceeded its {} limit", resource),
+            Outcome::TimedOut => "Timed out".to_string(),
+            Outcome::Cancelled => "Cancelled".to_string(),
+            Outcome::Error { message, .. } | Outcome::UnexpectedResponse { message, .. } => message.clone(),
+            Outcome::QueryFailed { sqlstate, message, .. } => {
+                format!("SQLSTATE {}: {}", sqlstate.as_deref().unwrap_or("unknown"), message)
+            }
+        };
+        Some(reason)
+    }
+
+    /// Converts an I/O error into an outcome. A missing program, missing permissions or
+    /// invalid input will not fix themselves, so those are not retryable.
+    pub fn from_io_error(err: &io::Error) -> Self {
+        Outcome::Error {
+            message: err.to_string(),
+            retryable: !matches!(
+                err.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied | io::ErrorKind::InvalidInput
+            ),
+        }
+    }
+}
+
+/// Everything an operator needs to run one attempt of a task.
+pub struct OperatorContext<'a> {
+    pub task: &'a Task,
+    pub workflow_id: usize,
+    /// Attempt number, starting at 1.
+    pub attempt: usize,
+    /// The attempt's stdout log. Anything written here is stored and streamed to live subscribers.
+    pub stdout: LogWriter,
+    /// The attempt's stderr log.
+    pub stderr: LogWriter,
+    pub cancel: &'a CancellationToken,
+    /// Outputs of the tasks this one depends on, keyed by task name.
+    pub upstream: &'a BTreeMap<String, serde_json::Value>,
+    /// File the task may write its output to as JSON.
+    pub output_file: PathBuf,
+    /// Small JSON result of the attempt, such as rows affected, handed to downstream tasks.
+    pub output: &'a mut Option<serde_json::Value>,
+}
+
+/// Runs the work of a task. Each task picks its operator through its `kind`.
+///
+/// Retries are handled by `Task::execute`; an operator only runs a single attempt. It must
+/// give up with `Outcome::TimedOut` once `ctx.task.timeout_duration` has passed, and with
+/// `Outcome::Cancelled` as soon as `ctx.cancel` is cancelled.
+#[async_trait]
+pub trait Operator: Send + Sync {
+    async fn run(&self, ctx: OperatorContext<'_>) -> Outcome;
+}
+
+/// The operators available to the scheduler, keyed by task `kind`.
+#[derive(Clone)]
+pub struct OperatorRegistry {
+    operators: HashMap<String, Arc<dyn Operator>>,
+}
+
+impl OperatorRegistry {
+    /// Creates a registry with the built-in operators:
+    /// - `shell`: runs the task's command as a child process.
+    /// - `sql`: runs SQL statements, against Thermidor's own database unless the task names
+    ///   another connection.
+    /// - `http`: sends an HTTP request and checks the response.
+    pub fn new(pool: PgPool) -> Self {
+        let mut registry = Self { operators: HashMap::new() };
+        registry.register("shell", shell::ShellOperator);
+        registry.register("sql", sql::SqlOperator::new(pool));
+        registry.register("http", http::HttpOperator::new());
+        registry
+    }
+
+    /// Registers an operator for a task kind, replacing any previous one.
+    pub fn register(&mut self, kind: &str, operator: impl Operator + 'static) -> &mut Self {
+        self.operators.insert(kind.to_string(), Arc::new(operator));
+        self
+    }
+
+    /// Returns the operator for a task kind.
+    pub fn get(&self, kind: &str) -> Option<Arc<dyn Operator>> {
+        self.operators.get(kind).cloned()
+    }
+}
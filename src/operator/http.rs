@@ -0,0 +1,273 @@
+use crate::operator::{Operator, OperatorContext, Outcome};
+use async_trait::async_trait;
+use reqwest::{Client, Method, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use serde_json_path::JsonPath;
+use std::collections::BTreeMap;
+use tokio::time::timeout;
+
+/// Number of bytes of the response body kept in the attempt's output.
+const MAX_STORED_BODY: usize = 4096;
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+/// Checks a value in a JSON response body.
+#[derive(Debug, Deserialize)]
+struct Assertion {
+    /// JSONPath expression, e.g. `$.status`. It must match at least one value.
+    path: String,
+    /// If set, every value the path matches must be equal to it.
+    #[serde(default)]
+    equals: Option<serde_json::Value>,
+}
+
+/// The `config` of an `http` task.
+#[derive(Debug, Deserialize)]
+struct HttpConfig {
+    #[serde(default = "default_method")]
+    method: String,
+    url: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    /// Request body. Strings are sent as they are, anything else as JSON.
+    #[serde(default)]
+    body: Option<serde_json::Value>,
+    /// Status codes that count as success. Any 2xx status if empty.
+    #[serde(default)]
+    expected_status: Vec<u16>,
+    #[serde(default)]
+    assertion: Option<Assertion>,
+}
+
+/// Sends an HTTP request and checks the response's status and, optionally, its JSON body.
+pub struct HttpOperator {
+    client: Client,
+}
+
+impl HttpOperator {
+    /// Creates an operator sharing one connection pool between all requests.
+    pub fn new() -> Self {
+        Self { client: Client::new() }
+    }
+
+    /// Returns whether a request that got an unexpected `status` could succeed if sent again:
+    /// server errors, rate limiting and request timeouts. Other client errors will not fix themselves.
+    fn retries_status(status: StatusCode) -> bool {
+        status.is_server_error()
+            || status == StatusCode::TOO_MANY_REQUESTS
+            || status == StatusCode::REQUEST_TIMEOUT
+    }
+
+    /// Checks the response body against the assertion, returning why it does not hold.
+    fn check(assertion: &Assertion, body: &[u8]) -> Result<(), String> {
+        let path = JsonPath::parse(&assertion.path)
+            .map_err(|err| format!("Invalid JSONPath '{}': {}", assertion.path, err))?;
+        let value: serde_json::Value = serde_json::from_slice(body)
+            .map_err(|err| format!("Response body is not JSON: {}", err))?;
+
+        let matched = path.query(&value).all();
+        if matched.is_empty() {
+            return Err(format!("'{}' matched nothing in the response body", assertion.path));
+        }
+        if let Some(expected) = &assertion.equals {
+            if let Some(actual) = matched.iter().find(|actual| **actual != expected) {
+                return Err(format!("'{}' is {}, expected {}", assertion.path, actual, expected));
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds the request described by a task's config.
+    fn build_request(&self, config: &HttpConfig) -> Result<reqwest::RequestBuilder, String> {
+        let method = Method::from_bytes(config.method.to_uppercase().as_bytes())
+            .map_err(|_| format!("Invalid HTTP method: {}", config.method))?;
+        let mut request = self.client.request(method, &config.url);
+        for (name, value) in &config.headers {
+            request = request.header(name, value);
+        }
+        request = match &config.body {
+            Some(serde_json::Value::String(body)) => request.body(body.clone()),
+            Some(body) => request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string()),
+            None => request,
+        };
+        Ok(request)
+    }
+}
+
+#[async_trait]
+impl Operator for HttpOperator {
+    async fn run(&self, ctx: OperatorContext<'_>) -> Outcome {
+        let OperatorContext { task, cancel, output, stdout: mut stdout_log, stderr: mut stderr_log, .. } = ctx;
+
+        let invalid = |message: String| Outcome::Error { message, retryable: false };
+        let config: HttpConfig = match serde_json::from_value(task.config.clone()) {
+            Ok(config) => config,
+            Err(err) => return invalid(format!("Invalid http task config: {}", err)),
+        };
+        let request = match self.build_request(&config) {
+            Ok(request) => request,
+            Err(message) => return invalid(message),
+        };
+
+        let exchange = async {
+            let response = request.send().await?;
+            let status = response.status();
+            let body = response.bytes().await?;
+            Ok::<_, reqwest::Error>((status, body))
+        };
+        let (status, body) = tokio::select! {
+            finished = timeout(task.timeout_duration, exchange) => match finished {
+                Ok(Ok(response)) => response,
+                Ok(Err(err)) => {
+                    let _ = stderr_log.write(format!("{}\n", err).as_bytes()).await;
+                    // A malformed URL will not fix itself, network errors may
+                    return Outcome::Error { message: err.to_string(), retryable: !err.is_builder() };
+                }
+                Err(_) => return Outcome::TimedOut,
+            },
+            _ = cancel.cancelled() => return Outcome::Cancelled,
+        };
+
+        let stored = &body[..body.len().min(MAX_STORED_BODY)];
+        let _ = stdout_log.write(format!("HTTP {}\n", status).as_bytes()).await;
+        let _ = stdout_log.write(stored).await;
+        *output = Some(json!({
+            "status": status.as_u16(),
+            "body": String::from_utf8_lossy(stored),
+            "truncated": body.len() > MAX_STORED_BODY,
+        }));
+
+        let expected = if config.expected_status.is_empty() {
+            status.is_success()
+        } else {
+            config.expected_status.contains(&status.as_u16())
+        };
+        if !expected {
+            return Outcome::UnexpectedResponse {
+                status: status.as_u16(),
+                message: format!("Unexpected status {}", status),
+                retryable: Self::retries_status(status),
+            };
+        }
+
+        if let Some(assertion) = &config.assertion {
+            if let Err(message) = Self::check(assertion, &body) {
+                let _ = stderr_log.write(format!("{}\n", message).as_bytes()).await;
+                // The endpoint may not be ready yet, e.g. while a job it reports on is running
+                return Outcome::UnexpectedResponse { status: status.as_u16(), message, retryable: true };
+            }
+        }
+
+        Outcome::Succeeded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logs::LogStore;
+    use crate::task::Task;
+    use axum::{http::StatusCode as Status, routing::{get, post}, Json, Router};
+    use serde_json::Value;
+    use std::net::SocketAddr;
+    use tokio_util::sync::CancellationToken;
+
+    /// Serves a few endpoints on a free local port and returns its address.
+    fn stub_server() -> SocketAddr {
+        let app = Router::new()
+            .route("/status", get(|| async { Json(json!({ "status": "done", "rows": 3 })) }))
+            .route("/unavailable", get(|| async { (Status::SERVICE_UNAVAILABLE, "try later") }))
+            .route("/missing", get(|| async { (Status::NOT_FOUND, "no such thing") }))
+            .route("/echo", post(|body: String| async move { body }));
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    /// Runs an `http` task with `config` against the stub server, returning its outcome and output.
+    async fn run(addr: SocketAddr, mut config: Value) -> (Outcome, Option<Value>) {
+        config["url"] = json!(format!("http://{}{}", addr, config["url"].as_str().unwrap()));
+        let mut task = Task::new(0, "request", "");
+        task.kind = "http".to_string();
+        task.config = config;
+
+        let logs = LogStore::new(std::env::temp_dir().join(format!("thermidor-http-test-{}", std::process::id())));
+        let (stdout, stderr) = logs.open_attempt(0, "test", 0, 1).await.unwrap();
+        let (cancel, upstream, mut output) = (CancellationToken::new(), BTreeMap::new(), None);
+        let ctx = OperatorContext {
+            task: &task,
+            workflow_id: 0,
+            attempt: 1,
+            stdout,
+            stderr,
+            cancel: &cancel,
+            upstream: &upstream,
+            output_file: logs.output_path(0, "test", 0, 1),
+            output: &mut output,
+        };
+        let outcome = HttpOperator::new().run(ctx).await;
+        (outcome, output)
+    }
+
+    #[tokio::test]
+    async fn succeeds_when_the_assertion_holds() {
+        let addr = stub_server();
+        let config = json!({ "url": "/status", "assertion": { "path": "$.status", "equals": "done" } });
+        let (outcome, output) = run(addr, config).await;
+        assert_eq!(outcome, Outcome::Succeeded);
+        let output = output.unwrap();
+        assert_eq!(output["status"], 200);
+        assert_eq!(output["truncated"], false);
+    }
+
+    #[tokio::test]
+    async fn sends_the_method_and_body() {
+        let addr = stub_server();
+        let config = json!({ "method": "post", "url": "/echo", "body": { "table": "orders" } });
+        let (outcome, output) = run(addr, config).await;
+        assert_eq!(outcome, Outcome::Succeeded);
+        assert_eq!(output.unwrap()["body"], r#"{"table":"orders"}"#);
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_and_client_errors_are_not() {
+        let addr = stub_server();
+        let (outcome, _) = run(addr, json!({ "url": "/unavailable" })).await;
+        assert!(matches!(outcome, Outcome::UnexpectedResponse { status: 503, retryable: true, .. }));
+        let (outcome, _) = run(addr, json!({ "url": "/missing" })).await;
+        assert!(matches!(outcome, Outcome::UnexpectedResponse { status: 404, retryable: false, .. }));
+        let (outcome, _) = run(addr, json!({ "url": "/missing", "expected_status": [404] })).await;
+        assert_eq!(outcome, Outcome::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_assertion_does_not_hold() {
+        let addr = stub_server();
+        let config = json!({ "url": "/status", "assertion": { "path": "$.rows", "equals": 4 } });
+        let (outcome, _) = run(addr, config).await;
+        match outcome {
+            Outcome::UnexpectedResponse { status, message, retryable } => {
+                assert_eq!(status, 200);
+                assert_eq!(message, "'$.rows' is 3, expected 4");
+                assert!(retryable);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+        let config = json!({ "url": "/status", "assertion": { "path": "$.missing" } });
+        let (outcome, _) = run(addr, config).await;
+        assert!(matches!(outcome, Outcome::UnexpectedResponse { retryable: true, .. }));
+    }
+
+    #[tokio::test]
+    async fn invalid_config_is_not_retried() {
+        let addr = stub_server();
+        let (outcome, _) = run(addr, json!({ "method": "NOT A METHOD", "url": "/status" })).await;
+        assert_eq!(outcome, Outcome::Error { message: "Invalid HTTP method: NOT A METHOD".to_string(), retryable: false });
+    }
+}
@@ -0,0 +1,357 @@
+use crate::limits;
+use crate::operator::{Operator, OperatorContext, Outcome};
+use crate::task::{ExecutionMode, Task};
+use async_trait::async_trait;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, Command};
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tracing::warn;
+
+/// Prefix of the stdout line a task publishes its output with, e.g. `::output::{"rows": 42}`.
+pub const OUTPUT_MARKER: &str = "::output::";
+
+/// Largest output a task may publish, in bytes.
+const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
+/// Bytes of stderr kept to recognise resource limit violations by their error messages.
+const STDERR_TAIL: usize = 64 * 1024;
+
+/// Runs a task's command as a child process in its own process group.
+///
+/// If the attempt runs past the task's timeout or the task is cancelled, the whole
+/// process group is terminated.
+pub struct ShellOperator;
+
+impl ShellOperator {
+    /// Builds the process to spawn for a task according to its execution mode,
+    /// environment and working directory.
+    fn build_command(task: &Task) -> io::Result<Command> {
+        let mut command = match task.mode {
+            ExecutionMode::Shell => {
+                let mut command = Command::new("/bin/sh");
+                command.arg("-c").arg(&task.command);
+                command
+            }
+            ExecutionMode::Exec => {
+                let (program, args) = task.argv.split_first().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "exec mode requires a non-empty argv")
+                })?;
+                let mut command = Command::new(program);
+                command.args(args);
+                command
+            }
+            ExecutionMode::Legacy => {
+                let parts: Vec<&str> = task.command.split_whitespace().collect();
+                let (cmd, args) = parts.split_first().unwrap_or((&"", &[]));
+                let mut command = Command::new(cmd);
+                command.args(args);
+                command
+            }
+        };
+
+        if task.clear_env {
+            command.env_clear();
+        }
+        command.envs(&task.env);
+        if let Some(dir) = &task.working_dir {
+            command.current_dir(dir);
+        }
+        if !task.limits.is_empty() {
+            let limits = task.limits.clone();
+            // SAFETY: `apply` only calls setrlimit, which is async-signal-safe.
+            unsafe {
+                command.pre_exec(move || limits.apply());
+            }
+        }
+
+        Ok(command)
+    }
+
+    /// Returns the environment variable an upstream task's output is passed in, e.g.
+    /// `THERMIDOR_UPSTREAM_LOAD_SALES` for a task named `load sales`.
+    fn upstream_var(task_name: &str) -> String {
+        let name: String = task_name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .collect();
+        format!("THERMIDOR_UPSTREAM_{}", name)
+    }
+
+    /// Reads the output a task published, preferring the `$THERMIDOR_OUTPUT` file over the
+    /// last marker line of its stdout. Marker values that are not JSON are kept as strings.
+    async fn read_output(output_file: &Path, marker_line: Option<String>) -> Result<Option<serde_json::Value>, String> {
+        let raw = match fs::read(output_file).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => match marker_line {
+                Some(line) => {
+                    if line.len() > MAX_OUTPUT_BYTES {
+                        return Err(format!("Output is larger than {} bytes", MAX_OUTPUT_BYTES));
+                    }
+                    let value = serde_json::from_str(&line).unwrap_or(serde_json::Value::String(line));
+                    return Ok(Some(value));
+                }
+                None => return Ok(None),
+            },
+            Err(err) => return Err(format!("Failed to read output file: {}", err)),
+        };
+
+        if raw.len() > MAX_OUTPUT_BYTES {
+            return Err(format!("Output is larger than {} bytes", MAX_OUTPUT_BYTES));
+        }
+        serde_json::from_slice(&raw)
+            .map(Some)
+            .map_err(|err| format!("Output file is not valid JSON: {}", err))
+    }
+
+    /// Sends SIGTERM to the task's process group, then SIGKILL to whatever is left of it once
+    /// `kill_grace_period` has passed.
+    async fn terminate(task: &Task, child: &mut Child) {
+        let Some(pid) = child.id() else {
+            return; // Already reaped
+        };
+        let group = -(pid as libc::pid_t);
+
+        warn!("Sending SIGTERM to process group {} of task '{}'", pid, task.name);
+        // SAFETY: kill(2) has no memory safety requirements.
+        unsafe { libc::kill(group, libc::SIGTERM) };
+
+        let deadline = Instant::now() + task.kill_grace_period;
+        loop {
+            // Reap the group leader as soon as it exits, so it doesn't keep the group alive.
+            let _ = child.try_wait();
+            // SAFETY: signal 0 only checks whether any process in the group still exists.
+            if unsafe { libc::kill(group, 0) } != 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                warn!("Sending SIGKILL to process group {} of task '{}'", pid, task.name);
+                // SAFETY: see above.
+                unsafe { libc::kill(group, libc::SIGKILL) };
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        let _ = child.wait().await;
+    }
+}
+
+#[async_trait]
+impl Operator for ShellOperator {
+    async fn run(&self, ctx: OperatorContext<'_>) -> Outcome {
+        let OperatorContext {
+            task,
+            workflow_id,
+            attempt,
+            cancel,
+            upstream,
+            output_file,
+            output,
+            stdout: mut stdout_log,
+            stderr: mut stderr_log,
+        } = ctx;
+
+        // Only what this attempt writes counts as its output
+        let _ = fs::remove_file(&output_file).await;
+        let output_file = std::path::absolute(&output_file).unwrap_or(output_file);
+
+        let spawned = Self::build_command(task).and_then(|mut command| {
+            // Let the process know where it runs
+            command
+                .env("THERMIDOR_WORKFLOW_ID", workflow_id.to_string())
+                .env("THERMIDOR_TASK_ID", task.id.to_string())
+                .env("THERMIDOR_ATTEMPT", attempt.to_string())
+                .env("THERMIDOR_OUTPUT", &output_file);
+            for (name, value) in upstream {
+                command.env(Self::upstream_var(name), value.to_string());
+            }
+
+            let stdin = if task.stdin.is_some() { Stdio::piped() } else { Stdio::null() };
+            command
+                .stdin(stdin)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .process_group(0)
+                .kill_on_drop(true)
+                .spawn()
+        });
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(err) => {
+                // Keep the reason the process could not be started next to the attempt's output
+                let _ = stderr_log.write(format!("{}\n", err).as_bytes()).await;
+                return Outcome::from_io_error(&err);
+            }
+        };
+
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+
+        // Feed stdin alongside reading the output, so a process that writes before it has
+        // consumed all of its input cannot block on a full pipe.
+        let feed_stdin = async {
+            if let (Some(mut pipe), Some(payload)) = (stdin, &task.stdin) {
+                match pipe.write_all(payload.as_bytes()).await {
+                    // The process may exit or close stdin without reading everything
+                    Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(err),
+                    _ => {}
+                }
+            }
+            Ok(())
+        };
+
+        // Keep the end of stderr, where the error that made the process exit usually is
+        let mut stderr_tail = Vec::new();
+        let keep_tail = |line: &[u8]| {
+            if !task.limits.is_empty() {
+                stderr_tail.extend_from_slice(line);
+                let excess = stderr_tail.len().saturating_sub(STDERR_TAIL);
+                stderr_tail.drain(..excess);
+            }
+        };
+
+        let mut marker_line = None;
+        let find_marker = |line: &[u8]| {
+            if let Some(value) = line.strip_prefix(OUTPUT_MARKER.as_bytes()) {
+                let value = String::from_utf8_lossy(value);
+                marker_line = Some(value.trim_end_matches(['\n', '\r']).to_string());
+            }
+        };
+
+        // Only a process that reached its CPU time hard limit counts as killed for it, which
+        // can only be read before the process is reaped.
+        let pid = child.id().filter(|_| task.limits.max_cpu_seconds.is_some());
+        let run = async {
+            let results = tokio::join!(
+                feed_stdin,
+                stdout_log.pump(stdout, find_marker),
+                stderr_log.pump(stderr, keep_tail)
+            );
+            let cpu_time = match pid {
+                Some(pid) => tokio::task::spawn_blocking(move || limits::cpu_time_at_exit(pid))
+                    .await
+                    .ok()
+                    .flatten(),
+                None => None,
+            };
+            (results, cpu_time, child.wait().await)
+        };
+
+        let finished = tokio::select! {
+            finished = timeout(task.timeout_duration, run) => finished,
+            _ = cancel.cancelled() => {
+                Self::terminate(task, &mut child).await;
+                return Outcome::Cancelled;
+            }
+        };
+
+        let ((stdin_result, stdout_result, stderr_result), cpu_time, status) = match finished {
+            Ok(finished) => finished,
+            Err(_) => {
+                Self::terminate(task, &mut child).await;
+                return Outcome::TimedOut;
+            }
+        };
+
+        let status = match (status, stdin_result, stdout_result, stderr_result) {
+            (Ok(status), Ok(()), Ok(()), Ok(())) => status,
+            (Err(err), ..) | (_, Err(err), ..) | (_, _, Err(err), _) | (.., Err(err)) => {
+                return Outcome::from_io_error(&err);
+            }
+        };
+
+        if status.success() {
+            return match Self::read_output(&output_file, marker_line).await {
+                Ok(value) => {
+                    *output = value;
+                    Outcome::Succeeded
+                }
+                Err(message) => {
+                    let _ = stderr_log.write(format!("{}\n", message).as_bytes()).await;
+                    Outcome::Error { message, retryable: false }
+                }
+            };
+        }
+
+        let stderr = String::from_utf8_lossy(&stderr_tail);
+        match task.limits.violated(status.code(), status.signal(), cpu_time, &stderr) {
+            Some(resource) => Outcome::LimitExceeded {
+                resource,
+                exit_code: status.code(),
+                signal: status.signal(),
+            },
+            None => Outcome::Failed {
+                exit_code: status.code(),
+                signal: status.signal(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    /// A file in a directory of its own for the test `name`, which does not exist yet.
+    async fn output_file(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("thermidor-shell-test-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let file = dir.join("output.json");
+        let _ = fs::remove_file(&file).await;
+        file
+    }
+
+    #[test]
+    fn upstream_task_names_are_turned_into_variable_names() {
+        assert_eq!(ShellOperator::upstream_var("load sales"), "THERMIDOR_UPSTREAM_LOAD_SALES");
+        assert_eq!(ShellOperator::upstream_var("extract-2.csv"), "THERMIDOR_UPSTREAM_EXTRACT_2_CSV");
+        assert_eq!(ShellOperator::upstream_var("Task 1"), "THERMIDOR_UPSTREAM_TASK_1");
+        assert_eq!(ShellOperator::upstream_var("café=$(rm)"), "THERMIDOR_UPSTREAM_CAF____RM_");
+    }
+
+    #[tokio::test]
+    async fn the_output_file_wins_over_the_marker_line() {
+        let file = output_file("file").await;
+        fs::write(&file, r#"{"rows": 42}"#).await.unwrap();
+        let output = ShellOperator::read_output(&file, Some("7".to_string())).await;
+        assert_eq!(output, Ok(Some(json!({ "rows": 42 }))));
+    }
+
+    #[tokio::test]
+    async fn the_marker_line_is_read_without_an_output_file() {
+        let file = output_file("marker").await;
+        assert_eq!(ShellOperator::read_output(&file, Some(r#"{"rows": 42}"#.to_string())).await, Ok(Some(json!({ "rows": 42 }))));
+        // Values that are not JSON are kept as they are
+        assert_eq!(ShellOperator::read_output(&file, Some("done".to_string())).await, Ok(Some(json!("done"))));
+        assert_eq!(ShellOperator::read_output(&file, None).await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn an_output_file_that_is_not_json_is_an_error() {
+        let file = output_file("invalid").await;
+        fs::write(&file, "rows: 42").await.unwrap();
+        let err = ShellOperator::read_output(&file, None).await.unwrap_err();
+        assert!(err.starts_with("Output file is not valid JSON"), "{}", err);
+        // Nor is an empty one
+        fs::write(&file, "").await.unwrap();
+        assert!(ShellOperator::read_output(&file, Some("1".to_string())).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn output_larger_than_the_limit_is_an_error() {
+        let file = output_file("large").await;
+        let large = format!("\"{}\"", "x".repeat(MAX_OUTPUT_BYTES));
+        let expected = Err(format!("Output is larger than {} bytes", MAX_OUTPUT_BYTES));
+        assert_eq!(ShellOperator::read_output(&file, Some(large.clone())).await, expected);
+        fs::write(&file, &large).await.unwrap();
+        assert_eq!(ShellOperator::read_output(&file, None).await, expected);
+    }
+}
@@ -0,0 +1,402 @@
+use crate::logs::LogWriter;
+use crate::operator::{Operator, OperatorContext, Outcome};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::{PgArguments, PgPool, Postgres};
+use sqlx::query::Query;
+use sqlx::types::Json;
+use sqlx::{Connection, PgConnection};
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tracing::warn;
+
+/// How long a cancelled statement may take to unwind before the attempt gives up on it.
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// The `config` of a `sql` task.
+#[derive(Debug, Deserialize)]
+struct SqlConfig {
+    /// Statements to run, in order. Each entry must be a single statement.
+    statements: Vec<Statement>,
+    /// Run all statements in one transaction, so either all or none of them take effect.
+    #[serde(default)]
+    transaction: bool,
+    /// Name of the connection to use. The database URL is read from
+    /// `THERMIDOR_CONN_<NAME>`; without a name, Thermidor's own database is used.
+    #[serde(default)]
+    connection: Option<String>,
+}
+
+/// A statement of a `sql` task: its SQL alone, or with the values of its `$1`, `$2`, ...
+/// parameters, which are sent apart from the SQL rather than pasted into it.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Statement {
+    Sql(String),
+    WithParams {
+        sql: String,
+        #[serde(default)]
+        params: Vec<Value>,
+    },
+}
+
+impl Statement {
+    /// Builds the query for the statement with its parameters bound. JSON strings, numbers
+    /// and booleans are bound as `text`, `bigint` or `double precision` and `boolean`, and
+    /// arrays and objects as `jsonb`.
+    fn query(&self) -> Query<'_, Postgres, PgArguments> {
+        let (sql, params) = match self {
+            Statement::Sql(sql) => (sql, &[][..]),
+            Statement::WithParams { sql, params } => (sql, &params[..]),
+        };
+        params.iter().fold(sqlx::query(sql), |query, param| match param {
+            Value::Null => query.bind(None::<String>),
+            Value::Bool(value) => query.bind(*value),
+            Value::Number(number) => match number.as_i64() {
+                Some(value) => query.bind(value),
+                None => query.bind(number.as_f64()),
+            },
+            Value::String(value) => query.bind(value.clone()),
+            value => query.bind(Json(value.clone())),
+        })
+    }
+}
+
+/// Why a statement could not be run.
+struct StatementError {
+    /// Position of the failed statement, or `None` if beginning or committing the transaction failed.
+    statement: Option<usize>,
+    error: sqlx::Error,
+}
+
+/// Runs SQL statements against a Postgres database.
+///
+/// A statement still running when the task times out or is cancelled is cancelled on the
+/// server with `pg_cancel_backend`.
+pub struct SqlOperator {
+    default_pool: PgPool,
+    pools: Mutex<HashMap<String, PgPool>>,
+}
+
+impl SqlOperator {
+    /// Creates an operator that runs tasks without a `connection` against `default_pool`.
+    pub fn new(default_pool: PgPool) -> Self {
+        Self {
+            default_pool,
+            pools: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the pool of a named connection, connecting to it on first use.
+    async fn pool(&self, connection: Option<&str>) -> Result<PgPool, Outcome> {
+        let Some(name) = connection else {
+            return Ok(self.default_pool.clone());
+        };
+
+        let mut pools = self.pools.lock().await;
+        if let Some(pool) = pools.get(name) {
+            return Ok(pool.clone());
+        }
+
+        let var = format!("THERMIDOR_CONN_{}", name.to_uppercase());
+        let url = std::env::var(&var).map_err(|_| Outcome::Error {
+            message: format!("Connection '{}' is not configured, set {}", name, var),
+            retryable: false,
+        })?;
+        let pool = PgPool::connect(&url).await.map_err(|err| Self::outcome(None, err))?;
+        pools.insert(name.to_string(), pool.clone());
+        Ok(pool)
+    }
+
+    /// Maps an error from sqlx to an outcome, keeping the SQLSTATE of database errors.
+    fn outcome(statement: Option<usize>, err: sqlx::Error) -> Outcome {
+        let message = match statement {
+            Some(statement) => format!("Statement {} failed: {}", statement, err),
+            None => err.to_string(),
+        };
+        match err {
+            sqlx::Error::Database(db_err) => {
+                let sqlstate = db_err.code().map(|code| code.into_owned());
+                let retryable = sqlstate.as_deref().is_some_and(Self::retries_sqlstate);
+                Outcome::QueryFailed { sqlstate, message, retryable }
+            }
+            // Connection problems and pool exhaustion are usually temporary
+            _ => Outcome::Error { message, retryable: true },
+        }
+    }
+
+    /// Returns whether a statement that failed with `sqlstate` could succeed if run again:
+    /// connection exceptions, serialization failures and deadlocks, insufficient resources
+    /// and operator intervention such as a server shutdown.
+    fn retries_sqlstate(sqlstate: &str) -> bool {
+        matches!(&sqlstate[..sqlstate.len().min(2)], "08" | "40" | "53" | "57")
+    }
+
+    /// Runs the statements on `conn`, in a transaction if configured, and returns a summary
+    /// of them.
+    async fn run_statements(
+        conn: &mut PgConnection,
+        config: &SqlConfig,
+        log: &mut LogWriter,
+    ) -> Result<serde_json::Value, StatementError> {
+        let results = if config.transaction {
+            let mut tx = conn.begin().await.map_err(|error| StatementError { statement: None, error })?;
+            let results = Self::run_each(&mut tx, &config.statements, log).await?;
+            tx.commit().await.map_err(|error| StatementError { statement: None, error })?;
+            results
+        } else {
+            Self::run_each(conn, &config.statements, log).await?
+        };
+
+        let rows_affected: u64 = results.iter().filter_map(|r| r["rows_affected"].as_u64()).sum();
+        Ok(json!({ "rows_affected": rows_affected, "statements": results }))
+    }
+
+    /// Runs each statement in turn, writing its rows affected and duration to `log`.
+    async fn run_each(
+        conn: &mut PgConnection,
+        statements: &[Statement],
+        log: &mut LogWriter,
+    ) -> Result<Vec<serde_json::Value>, StatementError> {
+        let mut results = Vec::new();
+        for (i, statement) in statements.iter().enumerate() {
+            let started = Instant::now();
+            let result = statement
+                .query()
+                .execute(&mut *conn)
+                .await
+                .map_err(|error| StatementError { statement: Some(i + 1), error })?;
+            let duration = started.elapsed();
+
+            let _ = log
+                .write(
+                    format!(
+                        "Statement {}: {} rows affected in {:.3}s\n",
+                        i + 1,
+                        result.rows_affected(),
+                        duration.as_secs_f64()
+                    )
+                    .as_bytes(),
+                )
+                .await;
+            results.push(json!({
+                "rows_affected": result.rows_affected(),
+                "duration": duration,
+            }));
+        }
+        Ok(results)
+    }
+
+    /// Cancels whatever the backend with the given pid is running. This goes through a
+    /// connection of its own, as every connection of the pool may be taken.
+    async fn cancel_backend(pool: &PgPool, pid: i32) {
+        let cancel = async {
+            let mut conn = PgConnection::connect_with(pool.connect_options()).await?;
+            sqlx::query("SELECT pg_cancel_backend($1)").bind(pid).execute(&mut conn).await?;
+            conn.close().await
+        };
+        match timeout(CANCEL_GRACE_PERIOD, cancel).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => warn!("Failed to cancel statement on backend {}: {}", pid, err),
+            Err(_) => warn!("Timed out cancelling statement on backend {}", pid),
+        }
+    }
+}
+
+#[async_trait]
+impl Operator for SqlOperator {
+    async fn run(&self, ctx: OperatorContext<'_>) -> Outcome {
+        let OperatorContext { task, cancel, output, stdout: mut stdout_log, stderr: mut stderr_log, .. } = ctx;
+        let started = Instant::now();
+
+        let config: SqlConfig = match serde_json::from_value(task.config.clone()) {
+            Ok(config) => config,
+            Err(err) => {
+                return Outcome::Error {
+                    message: format!("Invalid sql task config: {}", err),
+                    retryable: false,
+                };
+            }
+        };
+
+        let pool = match self.pool(config.connection.as_deref()).await {
+            Ok(pool) => pool,
+            Err(outcome) => return outcome,
+        };
+        let connect = async {
+            let mut conn: PoolConnection<Postgres> = pool.acquire().await?;
+            let pid: i32 = sqlx::query_scalar("SELECT pg_backend_pid()").fetch_one(&mut conn).await?;
+            Ok::<_, sqlx::Error>((conn, pid))
+        };
+        let (mut conn, pid) = tokio::select! {
+            connected = timeout(task.timeout_duration, connect) => match connected {
+                Ok(Ok(connected)) => connected,
+                Ok(Err(err)) => return Self::outcome(None, err),
+                Err(_) => return Outcome::TimedOut,
+            },
+            _ = cancel.cancelled() => return Outcome::Cancelled,
+        };
+
+        let mut work = Box::pin(Self::run_statements(&mut conn, &config, &mut stdout_log));
+        let remaining = task.timeout_duration.saturating_sub(started.elapsed());
+
+        let interrupted = tokio::select! {
+            result = &mut work => {
+                return match result {
+                    Ok(summary) => {
+                        *output = Some(summary);
+                        Outcome::Succeeded
+                    }
+                    Err(StatementError { statement, error }) => {
+                        let outcome = Self::outcome(statement, error);
+                        if let Outcome::QueryFailed { message, .. } | Outcome::Error { message, .. } = &outcome {
+                            let _ = stderr_log.write(format!("{}\n", message).as_bytes()).await;
+                        }
+                        outcome
+                    }
+                };
+            }
+            _ = sleep(remaining) => Outcome::TimedOut,
+            _ = cancel.cancelled() => Outcome::Cancelled,
+        };
+
+        // Stop the statement on the server and let it unwind, which also rolls back the
+        // transaction, so the connection goes back to the pool in a usable state.
+        warn!("Cancelling statement of task '{}' on backend {}", task.name, pid);
+        Self::cancel_backend(&pool, pid).await;
+        let unwound = timeout(CANCEL_GRACE_PERIOD, &mut work).await.is_ok();
+        drop(work);
+        if !unwound {
+            // Don't hand a connection that is still busy back to the pool
+            drop(conn.detach());
+        }
+        interrupted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logs::LogStore;
+    use crate::task::Task;
+    use std::collections::BTreeMap;
+    use tokio_util::sync::CancellationToken;
+
+    /// Connects to the database in `THERMIDOR_TEST_DATABASE_URL`. Tests that need one are
+    /// skipped without it.
+    async fn test_pool() -> Option<PgPool> {
+        let Ok(url) = std::env::var("THERMIDOR_TEST_DATABASE_URL") else {
+            eprintln!("THERMIDOR_TEST_DATABASE_URL is not set, skipping");
+            return None;
+        };
+        Some(PgPool::connect(&url).await.expect("test database is reachable"))
+    }
+
+    /// Runs a `sql` task with `config` on `pool`, cancelling it if `cancel` is, and returns
+    /// its outcome and output.
+    async fn run(pool: &PgPool, name: &str, config: Value, cancel: CancellationToken) -> (Outcome, Option<Value>) {
+        let mut task = Task::new(0, name, "");
+        task.kind = "sql".to_string();
+        task.config = config;
+
+        let logs = LogStore::new(std::env::temp_dir().join(format!("thermidor-sql-test-{}-{}", name, std::process::id())));
+        let (stdout, stderr) = logs.open_attempt(0, "test", 0, 1).await.unwrap();
+        let (upstream, mut output) = (BTreeMap::new(), None);
+        let ctx = OperatorContext {
+            task: &task,
+            workflow_id: 0,
+            attempt: 1,
+            stdout,
+            stderr,
+            cancel: &cancel,
+            upstream: &upstream,
+            output_file: logs.output_path(0, "test", 0, 1),
+            output: &mut output,
+        };
+        let outcome = SqlOperator::new(pool.clone()).run(ctx).await;
+        (outcome, output)
+    }
+
+    #[test]
+    fn statements_are_sql_or_sql_with_params() {
+        let config: SqlConfig = serde_json::from_value(json!({
+            "statements": ["SELECT 1", { "sql": "SELECT $1", "params": [1] }, { "sql": "SELECT 2" }]
+        }))
+        .unwrap();
+        assert!(matches!(&config.statements[0], Statement::Sql(sql) if sql == "SELECT 1"));
+        assert!(matches!(&config.statements[1], Statement::WithParams { params, .. } if params == &[json!(1)]));
+        assert!(matches!(&config.statements[2], Statement::WithParams { params, .. } if params.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn runs_several_statements_with_bound_params() {
+        let Some(pool) = test_pool().await else { return };
+        let table = format!("thermidor_sql_test_{}", std::process::id());
+        let config = json!({
+            "statements": [
+                format!("CREATE TEMP TABLE {} (day date, name text, rows bigint, ratio float8, ok boolean, extra jsonb)", table),
+                {
+                    "sql": format!("INSERT INTO {} VALUES ($1::date, $2, $3, $4, $5, $6), ($1::date, NULL, 0, 0, false, NULL)", table),
+                    "params": ["2024-03-01", "orders'); DROP TABLE x; --", 42, 0.5, true, { "source": "api" }]
+                },
+                {
+                    "sql": format!("UPDATE {} SET rows = rows + 1 WHERE name = $1 AND extra = $2 AND ok = $3", table),
+                    "params": ["orders'); DROP TABLE x; --", { "source": "api" }, true]
+                },
+                format!("DELETE FROM {} WHERE name IS NULL", table)
+            ],
+            "transaction": true
+        });
+        let (outcome, output) = run(&pool, "params", config, CancellationToken::new()).await;
+        assert_eq!(outcome, Outcome::Succeeded);
+        let output = output.unwrap();
+        let rows: Vec<_> = output["statements"].as_array().unwrap().iter().map(|s| s["rows_affected"].clone()).collect();
+        assert_eq!(rows, vec![json!(0), json!(2), json!(1), json!(1)]);
+        assert_eq!(output["rows_affected"], 4);
+    }
+
+    #[tokio::test]
+    async fn failed_statements_report_their_position_and_sqlstate() {
+        let Some(pool) = test_pool().await else { return };
+        let config = json!({ "statements": ["SELECT 1", { "sql": "SELECT $1::int", "params": ["not a number"] }] });
+        match run(&pool, "failed", config, CancellationToken::new()).await.0 {
+            Outcome::QueryFailed { sqlstate, message, retryable } => {
+                assert_eq!(sqlstate.as_deref(), Some("22P02"));
+                assert!(message.starts_with("Statement 2 failed"), "{}", message);
+                assert!(!retryable);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelling_stops_the_statement_even_when_the_pool_is_exhausted() {
+        let Some(pool) = test_pool().await else { return };
+        // The task takes the only connection, so cancelling must not wait for one
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(1)
+            .acquire_timeout(Duration::from_secs(1))
+            .connect_with(pool.connect_options().clone())
+            .await
+            .unwrap();
+        let cancel = CancellationToken::new();
+        let cancel_soon = {
+            let cancel = cancel.clone();
+            async move {
+                sleep(Duration::from_millis(500)).await;
+                cancel.cancel();
+            }
+        };
+        let started = Instant::now();
+        let config = json!({ "statements": ["SELECT pg_sleep(30)"] });
+        let ((outcome, _), ()) = tokio::join!(run(&pool, "cancel", config, cancel), cancel_soon);
+        assert_eq!(outcome, Outcome::Cancelled);
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+        // The connection went back to the pool in a usable state
+        let one: i32 = sqlx::query_scalar("SELECT 1").fetch_one(&pool).await.unwrap();
+        assert_eq!(one, 1);
+    }
+}
@@ -0,0 +1,178 @@
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The params a workflow accepts, declared as a subset of JSON Schema:
+///
+/// ```json
+/// {
+///   "properties": {"table": {"type": "string"}, "mode": {"enum": ["full", "incremental"]}},
+///   "required": ["table"],
+///   "additionalProperties": false
+/// }
+/// ```
+///
+/// Other JSON Schema keywords are ignored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParamsSchema {
+    #[serde(default)]
+    pub properties: BTreeMap<String, ParamSchema>,
+    /// Params that must be given.
+    #[serde(default)]
+    pub required: Vec<String>,
+    /// Whether params that are not in `properties` are accepted.
+    #[serde(default = "default_additional_properties", rename = "additionalProperties")]
+    pub additional_properties: bool,
+}
+
+fn default_additional_properties() -> bool {
+    true
+}
+
+impl Default for ParamsSchema {
+    /// A schema that accepts any params, as an empty one given as JSON does.
+    fn default() -> Self {
+        Self {
+            properties: BTreeMap::new(),
+            required: Vec::new(),
+            additional_properties: default_additional_properties(),
+        }
+    }
+}
+
+/// What a single param may be.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ParamSchema {
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<ParamType>,
+    /// The only values the param may take.
+    #[serde(default, rename = "enum", skip_serializing_if = "Option::is_none")]
+    pub allowed: Option<Vec<Value>>,
+}
+
+/// The JSON type of a param.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParamType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Object,
+    Array,
+    Null,
+}
+
+impl ParamType {
+    fn matches(&self, value: &Value) -> bool {
+        match self {
+            ParamType::String => value.is_string(),
+            ParamType::Number => value.is_number(),
+            ParamType::Integer => value.is_i64() || value.is_u64(),
+            ParamType::Boolean => value.is_boolean(),
+            ParamType::Object => value.is_object(),
+            ParamType::Array => value.is_array(),
+            ParamType::Null => value.is_null(),
+        }
+    }
+}
+
+impl ParamsSchema {
+    /// Checks `params` against the schema, and returns every problem found if they don't match.
+    pub fn validate(&self, params: &Map<String, Value>) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        for name in &self.required {
+            if !params.contains_key(name) {
+                errors.push(format!("Missing required param '{}'", name));
+            }
+        }
+        for (name, value) in params {
+            let Some(schema) = self.properties.get(name) else {
+                if !self.additional_properties {
+                    errors.push(format!("Unknown param '{}'", name));
+                }
+                continue;
+            };
+            if let Some(kind) = schema.kind.filter(|kind| !kind.matches(value)) {
+                errors.push(format!("Param '{}' must be of type {}, got {}", name, kind, value));
+            }
+            if let Some(allowed) = schema.allowed.as_ref().filter(|allowed| !allowed.contains(value)) {
+                let allowed: Vec<_> = allowed.iter().map(Value::to_string).collect();
+                errors.push(format!("Param '{}' must be one of {}, got {}", name, allowed.join(", "), value));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl fmt::Display for ParamType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            ParamType::String => "string",
+            ParamType::Number => "number",
+            ParamType::Integer => "integer",
+            ParamType::Boolean => "boolean",
+            ParamType::Object => "object",
+            ParamType::Array => "array",
+            ParamType::Null => "null",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> ParamsSchema {
+        serde_json::from_value(json!({
+            "properties": {
+                "table": {"type": "string"},
+                "limit": {"type": "integer"},
+                "mode": {"enum": ["full", "incremental"]}
+            },
+            "required": ["table"],
+            "additionalProperties": false
+        }))
+        .unwrap()
+    }
+
+    fn params(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn accepts_matching_params() {
+        let params = params(json!({ "table": "orders", "limit": 10, "mode": "full" }));
+        assert_eq!(schema().validate(&params), Ok(()));
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let params = params(json!({ "limit": 1.5, "mode": "partial", "extra": true }));
+        let errors = schema().validate(&params).unwrap_err();
+        assert_eq!(
+            errors,
+            [
+                "Missing required param 'table'",
+                "Unknown param 'extra'",
+                "Param 'limit' must be of type integer, got 1.5",
+                r#"Param 'mode' must be one of "full", "incremental", got "partial""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn accepts_any_params_by_default() {
+        let params = params(json!({ "anything": [1, 2] }));
+        assert_eq!(ParamsSchema::default().validate(&params), Ok(()));
+        let open: ParamsSchema = serde_json::from_value(json!({ "properties": {} })).unwrap();
+        assert_eq!(open.validate(&params), Ok(()));
+    }
+}
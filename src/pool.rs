@@ -0,0 +1,139 @@
+use crate::slots::{SlotTable, Slots};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// How much of a pool is in use.
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStatus {
+    pub name: String,
+    pub slots: usize,
+    /// Slots taken by running tasks.
+    pub occupied: usize,
+    /// Slots left for other tasks, none if the pool was shrunk below what is in use.
+    pub open: usize,
+    /// Number of tasks waiting for slots.
+    pub queued: usize,
+}
+
+/// Named pools of slots shared by the tasks of all workflows, which limit how many tasks
+/// using the same resource, such as a database, run at once.
+///
+/// A task takes some number of slots of its pool while it runs and gives them back when
+/// it finishes, so heavier tasks can be given more slots. The pools are kept in a slot table
+/// that the scheduler adds its own slots to, so tasks take their pool slots together with
+/// the others.
+#[derive(Clone, Default)]
+pub struct Pools {
+    pools: Arc<Mutex<BTreeMap<String, Slots>>>,
+    table: SlotTable,
+}
+
+impl Pools {
+    /// Creates an empty set of pools.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the pools described by `spec`, a comma-separated list of `name:slots`,
+    /// e.g. `warehouse:4,replica:2`. Each pool is named once and has at least one slot, as the
+    /// tasks of a pool without slots could never run.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let pools = Self::new();
+        for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (name, slots) = entry
+                .split_once(':')
+                .map(|(name, slots)| (name.trim(), slots.trim()))
+                .filter(|(name, _)| !name.is_empty())
+                .ok_or_else(|| format!("Invalid pool '{}', expected name:slots", entry))?;
+            let slots = slots
+                .parse()
+                .ok()
+                .filter(|slots| *slots > 0)
+                .ok_or_else(|| format!("Invalid number of slots for pool '{}': {}", name, slots))?;
+            if pools.get(name).is_ok() {
+                return Err(format!("Pool '{}' is defined more than once", name));
+            }
+            pools.set(name, slots);
+        }
+        Ok(pools)
+    }
+
+    /// Creates a pool, or changes its number of slots. Running tasks keep their slots if the
+    /// pool shrinks, but no task starts until enough of them are given back.
+    pub fn set(&self, name: &str, slots: usize) -> PoolStatus {
+        let mut pools = self.pools.lock().unwrap();
+        let pool = pools
+            .entry(name.to_string())
+            .or_insert_with(|| self.table.slots(&format!("Pool '{}'", name), slots));
+        pool.resize(slots);
+        status(name, pool)
+    }
+
+    /// Returns how much of every pool is in use, sorted by name.
+    pub fn status(&self) -> Vec<PoolStatus> {
+        let pools = self.pools.lock().unwrap();
+        pools.iter().map(|(name, pool)| status(name, pool)).collect()
+    }
+
+    /// Returns the slots of the pool `name`, or fails if there is no such pool.
+    pub fn get(&self, name: &str) -> Result<Slots, String> {
+        self.pools
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("Unknown pool '{}'", name))
+    }
+
+    /// Returns the table the pools are kept in.
+    pub fn table(&self) -> &SlotTable {
+        &self.table
+    }
+}
+
+fn status(name: &str, pool: &Slots) -> PoolStatus {
+    let usage = pool.usage();
+    PoolStatus {
+        name: name.to_string(),
+        slots: usage.capacity,
+        occupied: usage.occupied,
+        open: usage.capacity.saturating_sub(usage.occupied),
+        queued: usage.waiting,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slots(pools: &Pools) -> Vec<(String, usize)> {
+        pools.status().into_iter().map(|pool| (pool.name, pool.slots)).collect()
+    }
+
+    #[test]
+    fn pools_are_parsed_from_a_list_of_names_and_slots() {
+        let pools = Pools::parse(" warehouse : 4, replica:2 ,,").unwrap();
+        assert_eq!(slots(&pools), vec![("replica".to_string(), 2), ("warehouse".to_string(), 4)]);
+        assert!(Pools::parse("").unwrap().status().is_empty());
+    }
+
+    #[test]
+    fn malformed_pools_are_rejected() {
+        for spec in ["warehouse", "warehouse:", ":4", "warehouse:four", "warehouse:-1", "warehouse:4:2"] {
+            assert!(Pools::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn pools_without_slots_are_rejected() {
+        let err = Pools::parse("warehouse:4,replica:0").err().unwrap();
+        assert_eq!(err, "Invalid number of slots for pool 'replica': 0");
+    }
+
+    #[test]
+    fn pools_defined_twice_are_rejected() {
+        let err = Pools::parse("warehouse:4,replica:2,warehouse:8").err().unwrap();
+        assert_eq!(err, "Pool 'warehouse' is defined more than once");
+    }
+}
@@ -0,0 +1,123 @@
+use crate::task::Task;
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::visit::{Bfs, Reversed};
+use serde::{Serialize, Deserialize};
+use std::str::FromStr;
+use std::fmt;
+
+/// How a task's priority is worked out from its `priority_weight`.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WeightRule {
+    /// The task's own weight.
+    #[default]
+    Absolute,
+    /// The task's weight plus that of every task downstream of it, so tasks that much
+    /// depends on go first.
+    Downstream,
+    /// The task's weight plus that of every task upstream of it, so branches close to
+    /// finishing go first.
+    Upstream,
+}
+
+/// Returns the priority of a task in a workflow's graph: the higher, the sooner it gets
+/// slots when tasks wait for them.
+pub fn priority(graph: &DiGraph<Task, ()>, node: NodeIndex) -> i64 {
+    // Walks start at the task itself, so its own weight is included
+    let mut sum: i64 = 0;
+    match graph[node].weight_rule {
+        WeightRule::Absolute => return graph[node].priority_weight,
+        WeightRule::Downstream => {
+            let mut walk = Bfs::new(graph, node);
+            while let Some(next) = walk.next(graph) {
+                sum = sum.saturating_add(graph[next].priority_weight);
+            }
+        }
+        WeightRule::Upstream => {
+            let mut walk = Bfs::new(Reversed(graph), node);
+            while let Some(next) = walk.next(Reversed(graph)) {
+                sum = sum.saturating_add(graph[next].priority_weight);
+            }
+        }
+    }
+    sum
+}
+
+impl FromStr for WeightRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "absolute" => Ok(WeightRule::Absolute),
+            "downstream" => Ok(WeightRule::Downstream),
+            "upstream" => Ok(WeightRule::Upstream),
+            _ => Err(format!("Invalid weight rule: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for WeightRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rule = match self {
+            WeightRule::Absolute => "absolute",
+            WeightRule::Downstream => "downstream",
+            WeightRule::Upstream => "upstream",
+        };
+        write!(f, "{}", rule)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A diamond `a -> b, a -> c, b -> d, c -> d` with weights 1, 2, 4 and 8, all using `rule`.
+    fn diamond(rule: WeightRule) -> (DiGraph<Task, ()>, Vec<NodeIndex>) {
+        let mut graph = DiGraph::new();
+        let nodes: Vec<_> = [("a", 1), ("b", 2), ("c", 4), ("d", 8)]
+            .iter()
+            .enumerate()
+            .map(|(id, (name, weight))| {
+                let mut task = Task::new(id, name, "true");
+                task.priority_weight = *weight;
+                task.weight_rule = rule;
+                graph.add_node(task)
+            })
+            .collect();
+        for (from, to) in [(0, 1), (0, 2), (1, 3), (2, 3)] {
+            graph.add_edge(nodes[from], nodes[to], ());
+        }
+        (graph, nodes)
+    }
+
+    fn priorities(graph: &DiGraph<Task, ()>, nodes: &[NodeIndex]) -> Vec<i64> {
+        nodes.iter().map(|&node| priority(graph, node)).collect()
+    }
+
+    #[test]
+    fn absolute_priority_is_the_tasks_own_weight() {
+        let (graph, nodes) = diamond(WeightRule::Absolute);
+        assert_eq!(priorities(&graph, &nodes), vec![1, 2, 4, 8]);
+    }
+
+    #[test]
+    fn downstream_priority_counts_every_task_below_once() {
+        let (graph, nodes) = diamond(WeightRule::Downstream);
+        // `d` is reached through both branches of `a` but only counted once
+        assert_eq!(priorities(&graph, &nodes), vec![15, 10, 12, 8]);
+    }
+
+    #[test]
+    fn upstream_priority_counts_every_task_above_once() {
+        let (graph, nodes) = diamond(WeightRule::Upstream);
+        assert_eq!(priorities(&graph, &nodes), vec![1, 3, 5, 15]);
+    }
+
+    #[test]
+    fn each_task_follows_its_own_rule_and_sums_saturate() {
+        let (mut graph, nodes) = diamond(WeightRule::Absolute);
+        graph[nodes[0]].weight_rule = WeightRule::Downstream;
+        graph[nodes[3]].priority_weight = i64::MAX;
+        assert_eq!(priorities(&graph, &nodes), vec![i64::MAX, 2, 4, i64::MAX]);
+    }
+}
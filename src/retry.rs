@@ -0,0 +1,142 @@
+use serde::{Serialize, Deserialize};
+use tokio::time::Duration;
+use rand::Rng;
+
+/// How the delay between attempts grows.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackoffStrategy {
+    /// Wait `base_delay` before every retry.
+    Fixed,
+    /// Wait `base_delay * 2^n` before the n-th retry.
+    #[default]
+    Exponential,
+}
+
+/// Decides whether a failed attempt is retried and how long to wait before the next one.
+///
+/// Tasks without a policy of their own use the workflow's `default_retry_policy`.
+/// The number of retries is bounded separately by `Task::max_retries`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub strategy: BackoffStrategy,
+    pub base_delay: Duration,
+    /// Upper bound for the delay, including jitter.
+    pub max_delay: Option<Duration>,
+    /// Adds a random extra delay of up to this fraction of the computed delay (e.g. `0.2`).
+    pub jitter: f64,
+    /// If not empty, only these exit codes are retried.
+    pub retryable_exit_codes: Vec<i32>,
+    /// Exit codes that are never retried, such as usage errors.
+    pub non_retryable_exit_codes: Vec<i32>,
+    pub retry_on_timeout: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            strategy: BackoffStrategy::Exponential,
+            base_delay: Duration::from_secs(1),
+            max_delay: None,
+            jitter: 0.0,
+            retryable_exit_codes: Vec::new(),
+            non_retryable_exit_codes: Vec::new(),
+            retry_on_timeout: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns whether an attempt that exited with `code` should be retried.
+    /// `None` means the process was killed by a signal.
+    pub fn retries_exit_code(&self, code: Option<i32>) -> bool {
+        match code {
+            Some(code) => {
+                !self.non_retryable_exit_codes.contains(&code)
+                    && (self.retryable_exit_codes.is_empty() || self.retryable_exit_codes.contains(&code))
+            }
+            None => self.retryable_exit_codes.is_empty(),
+        }
+    }
+
+    /// Returns the delay before the given retry (1 for the first retry).
+    pub fn delay(&self, retry: usize) -> Duration {
+        let mut delay = match self.strategy {
+            BackoffStrategy::Fixed => self.base_delay,
+            BackoffStrategy::Exponential => {
+                let factor = 2u32.saturating_pow(retry.min(u32::MAX as usize) as u32);
+                self.base_delay.saturating_mul(factor)
+            }
+        };
+
+        if self.jitter > 0.0 {
+            let extra = rand::thread_rng().gen_range(0.0..=self.jitter);
+            let extra = Duration::try_from_secs_f64(delay.as_secs_f64() * extra).unwrap_or(Duration::MAX);
+            delay = delay.saturating_add(extra);
+        }
+
+        match self.max_delay {
+            Some(max_delay) => delay.min(max_delay),
+            None => delay,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(strategy: BackoffStrategy) -> RetryPolicy {
+        RetryPolicy { strategy, base_delay: Duration::from_secs(2), ..RetryPolicy::default() }
+    }
+
+    #[test]
+    fn fixed_delay_stays_the_same() {
+        let policy = policy(BackoffStrategy::Fixed);
+        assert_eq!(policy.delay(1), Duration::from_secs(2));
+        assert_eq!(policy.delay(5), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn exponential_delay_doubles_with_each_retry() {
+        let policy = policy(BackoffStrategy::Exponential);
+        assert_eq!(policy.delay(1), Duration::from_secs(4));
+        assert_eq!(policy.delay(2), Duration::from_secs(8));
+        assert_eq!(policy.delay(3), Duration::from_secs(16));
+    }
+
+    #[test]
+    fn delay_is_capped_and_does_not_overflow() {
+        let capped = RetryPolicy { max_delay: Some(Duration::from_secs(10)), ..policy(BackoffStrategy::Exponential) };
+        assert_eq!(capped.delay(3), Duration::from_secs(10));
+        assert_eq!(capped.delay(usize::MAX), Duration::from_secs(10));
+        // The factor stops growing instead of overflowing
+        assert_eq!(policy(BackoffStrategy::Exponential).delay(200), Duration::from_secs(2) * u32::MAX);
+    }
+
+    #[test]
+    fn jitter_adds_up_to_its_fraction_of_the_delay() {
+        let policy = RetryPolicy { jitter: 0.5, ..policy(BackoffStrategy::Fixed) };
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(3), "{:?}", delay);
+        }
+        let capped = RetryPolicy { max_delay: Some(Duration::from_secs(2)), ..policy };
+        assert_eq!(capped.delay(1), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn exit_codes_are_retried_as_configured() {
+        let policy = RetryPolicy {
+            retryable_exit_codes: vec![1, 2],
+            non_retryable_exit_codes: vec![2],
+            ..RetryPolicy::default()
+        };
+        assert!(policy.retries_exit_code(Some(1)));
+        assert!(!policy.retries_exit_code(Some(2)));
+        assert!(!policy.retries_exit_code(Some(3)));
+        assert!(!policy.retries_exit_code(None));
+        assert!(RetryPolicy::default().retries_exit_code(None));
+    }
+}
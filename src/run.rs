@@ -0,0 +1,190 @@
+use crate::state::TaskState;
+use crate::task::Task;
+use crate::template::RunContext;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+use std::str::FromStr;
+use std::fmt;
+
+/// What started a workflow run.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunTrigger {
+    /// Started by hand, or when the server starts.
+    Manual,
+    /// Started by the workflow's schedule.
+    Scheduled,
+    /// Started by a backfill of past schedule intervals.
+    Backfill,
+}
+
+/// Where a workflow run is at.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunState {
+    /// Waiting for other runs of the workflow to finish, see `max_active_runs`.
+    Queued,
+    Running,
+    /// Every task succeeded or was skipped.
+    Success,
+    /// A task failed.
+    Failed,
+    /// The run was cancelled before it could finish.
+    Cancelled,
+}
+
+impl RunState {
+    /// Works out how a run ended from the states its tasks ended in.
+    pub fn from_tasks<'a>(states: impl IntoIterator<Item = &'a TaskState>) -> Self {
+        let mut state = RunState::Success;
+        for task in states {
+            if task.is_failed() || !task.is_finished() {
+                return RunState::Failed;
+            }
+            if *task == TaskState::Cancelled {
+                state = RunState::Cancelled;
+            }
+        }
+        state
+    }
+}
+
+/// One run of a workflow: when and why it ran, and for which data interval.
+///
+/// The states of its tasks are kept apart, as `TaskInstance`s.
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkflowRun {
+    pub run_id: String,
+    pub workflow: String,
+    pub trigger: RunTrigger,
+    pub logical_date: DateTime<Utc>,
+    pub data_interval_start: DateTime<Utc>,
+    pub data_interval_end: DateTime<Utc>,
+    pub params: Map<String, Value>,
+    pub state: RunState,
+    pub start_time: DateTime<Utc>,
+    pub end_time: Option<DateTime<Utc>>,
+}
+
+impl WorkflowRun {
+    /// Queues a run described by `context`.
+    pub fn queue(context: &RunContext) -> Self {
+        Self {
+            run_id: context.run_id.clone(),
+            workflow: context.workflow.clone(),
+            trigger: context.trigger,
+            logical_date: context.logical_date,
+            data_interval_start: context.data_interval_start,
+            data_interval_end: context.data_interval_end,
+            params: context.params.clone(),
+            state: RunState::Queued,
+            start_time: Utc::now(),
+            end_time: None,
+        }
+    }
+
+    /// Describes the run again, so it can be resumed under the same id.
+    pub fn context(&self) -> RunContext {
+        RunContext {
+            run_id: self.run_id.clone(),
+            logical_date: self.logical_date,
+            data_interval_start: self.data_interval_start,
+            data_interval_end: self.data_interval_end,
+            workflow: self.workflow.clone(),
+            params: self.params.clone(),
+            trigger: self.trigger,
+        }
+    }
+
+    /// Starts the run once it is its turn.
+    pub fn start(&mut self) {
+        self.state = RunState::Running;
+        self.start_time = Utc::now();
+    }
+
+    /// Ends the run in `state`.
+    pub fn finish(&mut self, state: RunState) {
+        self.state = state;
+        self.end_time = Some(Utc::now());
+    }
+}
+
+/// The state of a task in one workflow run.
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskInstance {
+    pub task_id: usize,
+    pub name: String,
+    pub state: TaskState,
+    /// Number of attempts made so far.
+    pub attempts: usize,
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub output: Option<Value>,
+}
+
+impl From<&Task> for TaskInstance {
+    fn from(task: &Task) -> Self {
+        Self {
+            task_id: task.id,
+            name: task.name.clone(),
+            state: task.state.clone(),
+            attempts: task.attempts.len(),
+            start_time: task.start_time,
+            end_time: task.end_time,
+            output: task.output.clone(),
+        }
+    }
+}
+
+impl FromStr for RunTrigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "manual" => Ok(RunTrigger::Manual),
+            "scheduled" => Ok(RunTrigger::Scheduled),
+            "backfill" => Ok(RunTrigger::Backfill),
+            _ => Err(format!("Invalid run trigger: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for RunTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let trigger = match self {
+            RunTrigger::Manual => "manual",
+            RunTrigger::Scheduled => "scheduled",
+            RunTrigger::Backfill => "backfill",
+        };
+        write!(f, "{}", trigger)
+    }
+}
+
+impl FromStr for RunState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queued" => Ok(RunState::Queued),
+            "running" => Ok(RunState::Running),
+            "success" => Ok(RunState::Success),
+            "failed" => Ok(RunState::Failed),
+            "cancelled" => Ok(RunState::Cancelled),
+            _ => Err(format!("Invalid run state: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for RunState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            RunState::Queued => "queued",
+            RunState::Running => "running",
+            RunState::Success => "success",
+            RunState::Failed => "failed",
+            RunState::Cancelled => "cancelled",
+        };
+        write!(f, "{}", state)
+    }
+}
@@ -0,0 +1,114 @@
+use chrono::{DateTime, Duration, Timelike, Utc};
+use std::str::FromStr;
+use std::fmt;
+
+/// When a workflow runs on its own, given as a cron expression with seconds
+/// (`sec min hour day-of-month month day-of-week [year]`, e.g. `0 30 2 * * *`) or as one of
+/// the presets `@yearly`, `@monthly`, `@weekly`, `@daily` and `@hourly`.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    expression: String,
+    cron: cron::Schedule,
+}
+
+impl Schedule {
+    /// Returns the first time the schedule fires after `time`.
+    pub fn next_after(&self, time: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.cron.after(time).next()
+    }
+
+    /// Returns the last time the schedule fired before `time`.
+    pub fn previous_before(&self, time: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        // cron looks from a second before the time it is given, which would miss a fire time
+        // less than a second ago, so give it the next whole second
+        let time = match time.nanosecond() {
+            0 => *time,
+            nanos => *time + Duration::nanoseconds(1_000_000_000 - i64::from(nanos)),
+        };
+        self.cron.after(&time).next_back()
+    }
+
+    /// Returns the data intervals between `start` and `end`, oldest first: each runs from a fire
+    /// time to the next one, and only intervals that lie entirely within the range are returned.
+    pub fn intervals(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> impl Iterator<Item = (DateTime<Utc>, DateTime<Utc>)> + '_ {
+        // A fire time on `start` itself counts, but cron only looks after the time it is given
+        let mut fire_times = self
+            .cron
+            .after(&(start - Duration::seconds(1)))
+            .skip_while(move |time| *time < start)
+            .take_while(move |time| *time <= end)
+            .peekable();
+        std::iter::from_fn(move || {
+            let from = fire_times.next()?;
+            let to = *fire_times.peek()?;
+            Some((from, to))
+        })
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let cron = cron::Schedule::from_str(s.trim()).map_err(|err| format!("Invalid schedule '{}': {}", s, err))?;
+        Ok(Self { expression: s.trim().to_string(), cron })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, day, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn intervals_lie_entirely_within_the_range() {
+        let daily: Schedule = "0 30 2 * * *".parse().unwrap();
+        let intervals: Vec<_> = daily.intervals(at(1, 0, 0), at(4, 0, 0)).collect();
+        assert_eq!(intervals, [(at(1, 2, 30), at(2, 2, 30)), (at(2, 2, 30), at(3, 2, 30))]);
+    }
+
+    #[test]
+    fn intervals_include_fire_times_on_the_range_bounds() {
+        let daily: Schedule = "@daily".parse().unwrap();
+        let intervals: Vec<_> = daily.intervals(at(1, 0, 0), at(3, 0, 0)).collect();
+        assert_eq!(intervals, [(at(1, 0, 0), at(2, 0, 0)), (at(2, 0, 0), at(3, 0, 0))]);
+        assert_eq!(daily.intervals(at(1, 0, 0), at(1, 23, 0)).count(), 0);
+    }
+
+    #[test]
+    fn previous_before_finds_the_last_fire_time() {
+        let hourly: Schedule = "0 0 * * * *".parse().unwrap();
+        assert_eq!(hourly.previous_before(&at(1, 10, 30)), Some(at(1, 10, 0)));
+        // Strictly before, so the start of the interval ending at a fire time is found with it
+        assert_eq!(hourly.previous_before(&at(1, 10, 0)), Some(at(1, 9, 0)));
+        // A fire time a fraction of a second ago still counts
+        let just_after = at(1, 10, 0) + Duration::milliseconds(300);
+        assert_eq!(hourly.previous_before(&just_after), Some(at(1, 10, 0)));
+    }
+
+    #[test]
+    fn next_after_skips_the_current_fire_time() {
+        let hourly: Schedule = "@hourly".parse().unwrap();
+        assert_eq!(hourly.next_after(&at(1, 10, 0)), Some(at(1, 11, 0)));
+    }
+
+    #[test]
+    fn invalid_expressions_are_rejected() {
+        let err = "every day".parse::<Schedule>().unwrap_err();
+        assert!(err.starts_with("Invalid schedule 'every day'"), "{}", err);
+    }
+}
@@ -1,47 +1,202 @@
 use crate::workflow::Workflow;
 use crate::state::TaskState;
 use crate::task::Task;
+use crate::logs::LogStore;
+use crate::cancel::Cancellations;
+use crate::operator::{OperatorRegistry, Outcome};
+use crate::template::{unique_run_id, RunContext};
+use crate::history::History;
+use crate::run::{RunState, WorkflowRun};
+use crate::pool::Pools;
+use crate::priority::priority;
+use crate::slots::Slots;
+use crate::schedule::Schedule;
+use crate::backfill::{Backfill, Backfills, IntervalState};
+use crate::trigger::Decision;
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
 use petgraph::algo::toposort;
-use petgraph::graph::NodeIndex;
-use sqlx::PgPool;
-use std::collections::HashSet;
-use std::io::{self, Error, Write};
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::visit::Dfs;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{Error, Write};
 use tokio::task::JoinHandle;
 use tracing::{info, error};
-use futures::future::join_all;
+use futures::stream::{self, FuturesUnordered, StreamExt};
+use futures::FutureExt;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::sleep;
+use tokio::sync::mpsc::UnboundedSender;
 use std::path::Path;
 use std::fs;
 
-#[derive(Clone, Copy)]
-pub struct Scheduler;
+#[derive(Clone)]
+pub struct Scheduler {
+    operators: Arc<OperatorRegistry>,
+    logs: LogStore,
+    cancellations: Cancellations,
+    history: History,
+    /// Limits the number of tasks running at once across all workflows.
+    task_slots: Slots,
+    pools: Pools,
+    /// Limits the number of runs of each workflow going on at once, by workflow id.
+    run_slots: Arc<std::sync::Mutex<HashMap<usize, Slots>>>,
+}
+
+/// A workflow run in progress, shared with the tasks it started.
+struct ActiveRun {
+    context: RunContext,
+    /// Limits the number of tasks of the workflow running at once.
+    task_slots: Slots,
+    /// Priority of each task when it waits for slots.
+    priorities: HashMap<NodeIndex, i64>,
+    /// Tells the run that a queued task got its slots and is starting.
+    started: UnboundedSender<NodeIndex>,
+}
 
 impl Scheduler {
-    /// Creates a new Scheduler.
-    pub fn new() -> Self {
-        Self
+    /// Creates a new Scheduler that runs tasks with the operators registered for their kind,
+    /// stores task output in the given log store, stops tasks cancelled through `cancellations`
+    /// and records every attempt in `history`. At most `max_parallelism` tasks run at once, and
+    /// tasks that name a pool also wait for slots of that pool in `pools`.
+    pub fn new(
+        operators: OperatorRegistry,
+        logs: LogStore,
+        cancellations: Cancellations,
+        history: History,
+        max_parallelism: usize,
+        pools: Pools,
+    ) -> Self {
+        Self {
+            operators: Arc::new(operators),
+            logs,
+            cancellations,
+            history,
+            task_slots: pools.table().slots("Scheduler", max_parallelism.max(1)),
+            pools,
+            run_slots: Arc::default(),
+        }
     }
 
-    /// Executes a single task asynchronously.
-    async fn execute_task(node: NodeIndex, mut task: Task) -> Result<(NodeIndex, TaskState), String> {
+    /// Executes a single task asynchronously and returns it with its final state and timings.
+    ///
+    /// The task stays queued until the workflow, its pool and the scheduler all have free slots
+    /// for it, which it then takes at once, or until it is cancelled. Tasks with a higher
+    /// priority get slots first. It fails without running if its pool cannot take it.
+    async fn execute_task(
+        self,
+        node: NodeIndex,
+        mut task: Task,
+        workflow_id: usize,
+        run: Arc<ActiveRun>,
+        upstream: BTreeMap<String, serde_json::Value>,
+        cancel: CancellationToken,
+    ) -> Result<(NodeIndex, Task), String> {
+        info!("Queueing task: {}", task.name);
+
+        // Held until the task finishes. A task cancelled while queued goes on without them
+        // and stops straight away.
+        let priority = run.priorities.get(&node).copied().unwrap_or(task.priority_weight);
+        let slots = tokio::select! {
+            slots = async {
+                let pool = task.pool.as_deref().map(|pool| self.pools.get(pool)).transpose()?;
+                let mut wanted = vec![(&run.task_slots, 1), (&self.task_slots, 1)];
+                if let Some(pool) = &pool {
+                    wanted.push((pool, task.pool_slots));
+                }
+                self.pools.table().acquire(&wanted, priority).await
+            } => Some(slots),
+            _ = cancel.cancelled() => None,
+        };
+        let _slots = match slots {
+            Some(Err(message)) => {
+                error!("Task '{}' cannot be started: {}", task.name, message);
+                set_state(&mut task, TaskState::Failure);
+                task.last_outcome = Some(Outcome::Error { message, retryable: false });
+                return Ok((node, task));
+            }
+            slots => slots,
+        };
+        let _ = run.started.send(node);
         info!("Scheduling task: {}", task.name);
+
+        let Some(operator) = self.operators.get(&task.kind) else {
+            error!("Task '{}' has unknown kind '{}'", task.name, task.kind);
+            if let Err(err) = task.state.transition(TaskState::Failure) {
+                error!("Task '{}': {}", task.name, err);
+            }
+            task.last_outcome = Some(Outcome::Error {
+                message: format!("No operator registered for kind '{}'", task.kind),
+                retryable: false,
+            });
+            return Ok((node, task));
+        };
     
-        let state = if let Err(err) = task.execute().await {
+        if let Err(err) = task.execute(operator.as_ref(), workflow_id, &run.context, &upstream, &self.logs, &cancel).await {
             error!("Task '{}' failed: {}", task.name, err);
-            TaskState::Failure
         } else {
             info!("Task '{}' completed successfully.", task.name);
-            TaskState::Success
-        };
+        }
+
+        if let Err(err) = self.history.record_attempts(&run.context.workflow, &run.context.run_id, &task).await {
+            error!("Failed to record the attempts of task '{}': {}", task.name, err);
+        }
     
         // Force flush of the log buffer
         std::io::stdout().flush().unwrap();
     
-        Ok((node, state))
+        Ok((node, task))
     }
     
 
+    /// Starts a new run of the workflow described by `context`, with every task reset, and
+    /// returns the run once it has finished.
+    pub async fn run(
+        &self,
+        workflow_id: usize,
+        workflow: &Mutex<Workflow>,
+        save_path: &str,
+        context: RunContext,
+    ) -> Result<WorkflowRun, Error> {
+        self.execute_run(workflow_id, workflow, save_path, context, true).await
+    }
+
+    /// Runs the workflow from the task states it was saved with, so a run interrupted by a
+    /// restart carries on where it stopped instead of starting over. `context` is that of the
+    /// interrupted run, which is reopened.
+    pub async fn resume(
+        &self,
+        workflow_id: usize,
+        workflow: &Mutex<Workflow>,
+        save_path: &str,
+        context: RunContext,
+    ) -> Result<WorkflowRun, Error> {
+        self.execute_run(workflow_id, workflow, save_path, context, false).await
+    }
+
     /// Runs the tasks in the workflow based on their dependencies, with parallel execution and state persistence.
-    pub async fn run(&self, workflow: &mut Workflow, save_path: &str) -> Result<(), Error> {
+    ///
+    /// The run works on its own copy of the workflow's tasks, so several runs of a workflow can
+    /// go on at once. Each change of a task's state is recorded in `history` as the run goes,
+    /// and the latest run of the workflow is also shown in, and saved with, the workflow itself.
+    ///
+    /// Tasks start as soon as their trigger rule is met: every time a task finishes, its downstream
+    /// tasks are looked at again, rather than waiting for other running tasks. The workflow is only
+    /// locked while its state is updated, so it can be queried while tasks run.
+    ///
+    /// Tasks that are ready but wait for a free slot, because of the workflow's `max_active_tasks`
+    /// or the scheduler's maximum parallelism, are `Queued`. The run itself is queued until there
+    /// are fewer than `max_active_runs` runs of the workflow going on; waiting runs start in the
+    /// order they came in.
+    async fn execute_run(
+        &self,
+        workflow_id: usize,
+        workflow: &Mutex<Workflow>,
+        save_path: &str,
+        context: RunContext,
+        fresh: bool,
+    ) -> Result<WorkflowRun, Error> {
         // Ensure the directory exists
         if let Some(parent_dir) = Path::new(save_path).parent() {
             if !parent_dir.exists() {
@@ -49,167 +204,663 @@ impl Scheduler {
             }
         }
 
-        // Track completed tasks for resumed workflows
-        let mut completed = HashSet::new();
+        let mut record = WorkflowRun::queue(&context);
+        // The run can be cancelled from now on, even while it is queued
+        let cancel = self.cancellations.start(workflow_id, &record.run_id);
+        if let Err(err) = self.history.record_run(&record).await {
+            error!("Failed to record run '{}': {}", record.run_id, err);
+        }
+        workflow.lock().await.starting_runs.retain(|run_id| *run_id != record.run_id);
+        let run_slots = {
+            let max_active_runs = workflow.lock().await.max_active_runs.unwrap_or(usize::MAX).max(1);
+            let mut run_slots = self.run_slots.lock().unwrap();
+            let slots = run_slots.entry(workflow_id).or_insert_with(|| Slots::new("Workflow runs", max_active_runs));
+            slots.resize(max_active_runs);
+            slots.clone()
+        };
+        let _run_slot = tokio::select! {
+            slot = run_slots.acquire(1, 0) => slot.map_err(Error::other)?,
+            _ = cancel.cancelled() => {
+                info!("Run '{}' of workflow '{}' was cancelled while queued", record.run_id, record.workflow);
+                record.finish(RunState::Cancelled);
+                if let Err(err) = self.history.record_run(&record).await {
+                    error!("Failed to record run '{}': {}", record.run_id, err);
+                }
+                self.cancellations.finish(workflow_id, &record.run_id);
+                return Ok(record);
+            }
+        };
 
-        for node in workflow.graph.node_indices() {
-            match workflow.graph[node].state {
-                TaskState::Success => {
-                    // Mark already completed tasks as completed
-                    completed.insert(node);
+        record.start();
+        let (started, mut starting) = mpsc::unbounded_channel();
+        let (run, mut graph, mut candidates) = {
+            let mut workflow = workflow.lock().await;
+            info!("Starting run '{}' of workflow '{}'", context.run_id, workflow.name);
+
+            let mut graph = workflow.graph.clone();
+            for task in graph.node_weights_mut() {
+                if fresh {
+                    task.reset();
+                } else if matches!(task.state, TaskState::Skipped | TaskState::UpstreamFailed) {
+                    // Whether a task is skipped is decided again in every run
+                    set_state(task, TaskState::Pending);
+                } else if matches!(task.state, TaskState::Queued | TaskState::Running | TaskState::UpForRetry) {
+                    task.requeue();
                 }
-                TaskState::Failure => {
-                    // Handle failed tasks loaded from json
-                    // Reset failed or skipped tasks to Pending
-                    //  workflow.graph[node].state = TaskState::Pending;
+                if task.retry_policy.is_none() {
+                    task.retry_policy = Some(workflow.default_retry_policy.clone());
                 }
-                _ => {} // Leave Pending and Running tasks as they are
             }
+
+            // Every task is looked at once up front, in dependency order
+            let order = match toposort(&graph, None) {
+                Ok(order) => order,
+                Err(err) => {
+                    error!("Cycle detected in workflow: {:?}", err);
+                    drop(workflow);
+                    record.finish(RunState::Failed);
+                    if let Err(err) = self.history.record_run(&record).await {
+                        error!("Failed to record run '{}': {}", record.run_id, err);
+                    }
+                    self.cancellations.finish(workflow_id, &record.run_id);
+                    return Err(Error::other("Cycle detected in workflow"));
+                }
+            };
+            let run = Arc::new(ActiveRun {
+                task_slots: self.pools.table().slots("Workflow", workflow.max_active_tasks.unwrap_or(usize::MAX).max(1)),
+                priorities: graph.node_indices().map(|node| (node, priority(&graph, node))).collect(),
+                context,
+                started,
+            });
+            workflow.active_runs.push(run.context.run_id.clone());
+            (run, graph, VecDeque::from(order))
+        };
+        if let Err(err) = self.history.record_run(&record).await {
+            error!("Failed to record run '{}': {}", record.run_id, err);
         }
-   
+
+        let mut published = HashMap::new();
+        let mut running = FuturesUnordered::new();
         loop {
-            let mut running_tasks: Vec<JoinHandle<Result<(NodeIndex, TaskState), String>>> = Vec::new();
-            let mut progress_made = false;
-    
-            match toposort(&workflow.graph, None) {
-                Ok(order) => {
-                    for node in order {
-                        running_tasks.retain(|handle| !handle.is_finished());
-    
-                        if workflow.graph[node].state == TaskState::Pending || workflow.graph[node].state == TaskState::Skipped {
-                            let all_deps_completed = workflow
-                                .graph
-                                .neighbors_directed(node, petgraph::Incoming)
-                                .all(|dep| completed.contains(&dep));
-    
-                            if all_deps_completed {
-                                let task = workflow.graph[node].clone();
-                                let handle = tokio::spawn(Self::execute_task(node, task));
-                                running_tasks.push(handle);
-                                progress_made = true;
-                            } else {
-                                info!("Skipping task: {} due to incomplete dependencies", workflow.graph[node].name);
-                                workflow.graph[node].state = TaskState::Skipped;
-                            }
-                        }
+            while let Some(node) = candidates.pop_front() {
+                if let Some(handle) = self.consider(workflow_id, &mut graph, node, &run, &mut candidates) {
+                    running.push(handle.map(move |result| (node, result)));
+                }
+            }
+            self.publish(workflow, save_path, &run.context, &graph, &mut published).await;
+
+            if running.is_empty() {
+                break;
+            }
+            // Wait for whichever task starts or finishes first. A task always reports that it
+            // started before it finishes, so starts are looked at first.
+            let (node, result) = tokio::select! {
+                biased;
+                Some(node) = starting.recv() => {
+                    set_state(&mut graph[node], TaskState::Running);
+                    self.publish(workflow, save_path, &run.context, &graph, &mut published).await;
+                    continue;
+                }
+                Some(finished) = running.next() => finished,
+            };
+
+            match result {
+                Ok(Ok((node, task))) => {
+                    graph[node] = task;
+                }
+                Ok(Err(err)) => {
+                    error!("Task execution error: {}", err);
+                    set_state(&mut graph[node], TaskState::Failure);
+                }
+                Err(join_err) => {
+                    error!("Join error: {}", join_err);
+                    set_state(&mut graph[node], TaskState::Failure);
+                }
+            }
+            settle(&mut graph, node, &mut candidates);
+        }
+
+        record.finish(RunState::from_tasks(graph.node_weights().map(|task| &task.state)));
+        info!("Run '{}' of workflow '{}' finished: {}", record.run_id, record.workflow, record.state);
+        if let Err(err) = self.history.record_run(&record).await {
+            error!("Failed to record run '{}': {}", record.run_id, err);
+        }
+        workflow.lock().await.active_runs.retain(|run_id| *run_id != record.run_id);
+        self.cancellations.finish(workflow_id, &record.run_id);
+
+        Ok(record)
+    }
+
+    /// Records the tasks of a run whose state changed since the last call in `history`, and
+    /// shows them in the workflow if this is its latest run. `published` keeps the states
+    /// recorded so far.
+    async fn publish(
+        &self,
+        workflow: &Mutex<Workflow>,
+        save_path: &str,
+        run: &RunContext,
+        graph: &DiGraph<Task, ()>,
+        published: &mut HashMap<NodeIndex, TaskState>,
+    ) {
+        let changed: Vec<_> = graph
+            .node_indices()
+            .filter(|node| published.get(node) != Some(&graph[*node].state))
+            .collect();
+        if changed.is_empty() {
+            return;
+        }
+
+        {
+            let mut workflow = workflow.lock().await;
+            if workflow.active_runs.last() == Some(&run.run_id) {
+                workflow.run_id = Some(run.run_id.clone());
+                for node in graph.node_indices() {
+                    if let Some(task) = workflow.graph.node_weight_mut(node) {
+                        task.copy_run_state(&graph[node]);
                     }
-    
-                    // Collect completed tasks
-                    let completed_nodes = join_all(running_tasks).await;
-                    for result in completed_nodes {
-                        match result {
-                            Ok(Ok((node, state))) => {
-                                workflow.graph[node].state = state;
-                                completed.insert(node);
-    
-                                // Save the workflow state after each task execution
-                                if let Err(err) = workflow.save_to_json(&save_path) {
-                                    error!("Failed to save workflow state: {}", err);
-                                }
-                            }
-                            Ok(Err(err)) => {
-                                error!("Task execution error: {}", err);
-                            }
-                            Err(join_err) => {
-                                error!("Join error: {}", join_err);
-                            }
-                        }
+                }
+                if let Err(err) = workflow.save_to_json(save_path) {
+                    error!("Failed to save workflow state: {}", err);
+                }
+            }
+        }
+
+        for node in changed {
+            if let Err(err) = self.history.record_task(&run.workflow, &run.run_id, &graph[node]).await {
+                error!("Failed to record the state of task '{}': {}", graph[node].name, err);
+            }
+            published.insert(node, graph[node].state.clone());
+        }
+    }
+
+    /// Returns where the schedule of a workflow picks up from when Thermidor starts: the end of
+    /// the interval of the last finished scheduled run recorded in `history`, so the intervals
+    /// missed while Thermidor was down are not lost. Runs interrupted by the restart do not count.
+    pub async fn schedule_start(&self, workflow: &str, schedule: &Schedule) -> DateTime<Utc> {
+        let last_run = match self.history.last_scheduled_interval_end(workflow).await {
+            Ok(last_run) => last_run,
+            Err(err) => {
+                error!("Failed to find the last scheduled run of workflow '{}': {}", workflow, err);
+                None
+            }
+        };
+        // Without any run to go on from, the first interval is the one ending at the next fire time
+        last_run
+            .or_else(|| schedule.previous_before(&Utc::now()))
+            .unwrap_or_else(Utc::now)
+    }
+
+    /// Marks the runs of a workflow that a stopped server left queued or running as failed in
+    /// `history`, since nothing will finish them, and returns their ids.
+    pub async fn fail_interrupted_runs(&self, workflow: &str) -> Vec<String> {
+        match self.history.fail_interrupted_runs(workflow).await {
+            Ok(run_ids) => {
+                if !run_ids.is_empty() {
+                    info!("Marked interrupted runs of workflow '{}' as failed: {}", workflow, run_ids.join(", "));
+                }
+                run_ids
+            }
+            Err(err) => {
+                error!("Failed to mark interrupted runs of workflow '{}' as failed: {}", workflow, err);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Describes the run `run_id` of a workflow as it was recorded in `history`, so it can be
+    /// resumed under the same id.
+    pub async fn recorded_run(&self, workflow: &str, run_id: &str) -> Option<RunContext> {
+        match self.history.run(workflow, run_id).await {
+            Ok(run) => run.map(|(run, _)| run.context()),
+            Err(err) => {
+                error!("Failed to look up run '{}' of workflow '{}': {}", run_id, workflow, err);
+                None
+            }
+        }
+    }
+
+    /// Runs the workflow every time `schedule` fires, each run processing the data interval
+    /// since the previous fire time, until the schedule has no more fire times.
+    ///
+    /// Intervals are picked up after `after`, see `schedule_start`. If the workflow has `catchup`,
+    /// every missed interval is run, oldest first and at most `max_active_runs` at once; otherwise
+    /// only the latest one is. The same goes for fire times that pass while runs are still going.
+    pub async fn run_on_schedule(
+        &self,
+        workflow_id: usize,
+        workflow: &Mutex<Workflow>,
+        save_path: &str,
+        schedule: Schedule,
+        mut after: DateTime<Utc>,
+    ) {
+        loop {
+            let (catchup, max_active_runs) = {
+                let workflow = workflow.lock().await;
+                (workflow.catchup, workflow.max_active_runs)
+            };
+            let now = Utc::now();
+            let mut from = after;
+            if !catchup {
+                // Only the latest interval is run, no need to look at those before it
+                let latest_start = schedule
+                    .previous_before(&now)
+                    .and_then(|latest_end| schedule.previous_before(&latest_end));
+                from = from.max(latest_start.unwrap_or(from));
+            }
+            let missed: Vec<_> = schedule.intervals(from, now).collect();
+            if missed.is_empty() {
+                let Some(next) = schedule.next_after(&after.max(Utc::now())) else {
+                    info!("Schedule '{}' of workflow {} has no more fire times", schedule, workflow_id);
+                    return;
+                };
+                info!("Next run of workflow {} at {}", workflow_id, next);
+                sleep((next - Utc::now()).to_std().unwrap_or_default()).await;
+                continue;
+            }
+
+            if from > after {
+                info!("Skipping missed intervals of workflow {} before {}", workflow_id, from);
+            }
+            if missed.len() > 1 {
+                info!("Catching up on {} missed intervals of workflow {}", missed.len() - 1, workflow_id);
+            }
+            after = missed[missed.len() - 1].1;
+
+            stream::iter(missed)
+                .for_each_concurrent(max_active_runs, |(start, end)| async move {
+                    let mut context = {
+                        let workflow = workflow.lock().await;
+                        RunContext::scheduled(&workflow.name, &workflow.params, start, end)
+                    };
+                    // An interval whose run was interrupted by a restart is run again under a new id
+                    match self.history.run_ids_starting_with(&context.workflow, &context.run_id).await {
+                        Ok(taken) => context.run_id = unique_run_id(&context.run_id, &taken),
+                        Err(err) => error!("Failed to look up the runs of workflow {}: {}", workflow_id, err),
                     }
-    
-                    // If no progress was made and there are still skipped tasks, break the loop
-                    if !progress_made {
-                        break;
+                    if let Err(err) = self.run(workflow_id, workflow, save_path, context).await {
+                        error!("Error running workflow {}: {}", workflow_id, err);
                     }
+                })
+                .await;
+        }
+    }
+
+    /// Runs the pending intervals of a backfill of the workflow, oldest first, with at most
+    /// `max_active_runs` of them going on at once, and keeps the backfill's progress up to date
+    /// in `backfills`.
+    pub async fn backfill(
+        &self,
+        workflow_id: usize,
+        workflow: &Mutex<Workflow>,
+        save_path: &str,
+        backfills: &Backfills,
+        backfill: Backfill,
+    ) {
+        info!(
+            "Starting backfill {} of workflow '{}' from {} to {}",
+            backfill.id, backfill.workflow, backfill.start, backfill.end
+        );
+        let pending: Vec<_> = backfill
+            .intervals
+            .iter()
+            .enumerate()
+            .filter(|(_, interval)| interval.state == IntervalState::Pending)
+            .map(|(index, interval)| (index, interval.clone()))
+            .collect();
+
+        let id = backfill.id;
+        let name = &backfill.workflow;
+        stream::iter(pending)
+            .for_each_concurrent(backfill.max_active_runs, |(index, interval)| async move {
+                let context = {
+                    let workflow = workflow.lock().await;
+                    let (start, end) = (interval.data_interval_start, interval.data_interval_end);
+                    // The backfill picked a run id that no earlier run over the interval has
+                    RunContext {
+                        run_id: interval.run_id,
+                        ..RunContext::backfill(&workflow.name, &workflow.params, start, end)
+                    }
+                };
+                // Cancellable before it is marked running, so cancelling the backfill reaches it
+                self.cancellations.start(workflow_id, &context.run_id);
+                if !backfills.start_interval(id, index) {
+                    self.cancellations.finish(workflow_id, &context.run_id);
+                    return;
                 }
-                Err(err) => {
-                    error!("Cycle detected in workflow: {:?}", err);
-                    return Err(Error::new(std::io::ErrorKind::Other, "Cycle detected in workflow"));
-                }
+                let state = match self.run(workflow_id, workflow, save_path, context).await {
+                    Ok(run) => run.state.into(),
+                    Err(err) => {
+                        error!("Error running backfill {} of workflow {}: {}", id, workflow_id, err);
+                        IntervalState::Failed
+                    }
+                };
+                let progress = backfills.set_state(id, index, state);
+                info!(
+                    "Backfill {} of workflow '{}': {} of {} intervals done",
+                    id,
+                    name,
+                    progress.total - progress.pending - progress.running,
+                    progress.total
+                );
+            })
+            .await;
+
+        if let Some(backfill) = backfills.get(id) {
+            info!("Backfill {} of workflow '{}' finished: {}", id, name, backfill.state);
+        }
+    }
+
+    /// Decides what to do with a task now that its upstream tasks may have changed state, and
+    /// starts it if its trigger rule is met. Tasks that are not pending are left alone.
+    ///
+    /// Tasks that are settled without running have their downstream tasks added to `candidates`.
+    fn consider(
+        &self,
+        workflow_id: usize,
+        graph: &mut DiGraph<Task, ()>,
+        node: NodeIndex,
+        run: &Arc<ActiveRun>,
+        candidates: &mut VecDeque<NodeIndex>,
+    ) -> Option<JoinHandle<Result<(NodeIndex, Task), String>>> {
+        if graph[node].state != TaskState::Pending {
+            return None;
+        }
+
+        let cancel = self.cancellations.task(workflow_id, &run.context.run_id, graph[node].id);
+        let upstream_cancelled = graph
+            .neighbors_directed(node, petgraph::Incoming)
+            .any(|dep| graph[dep].state == TaskState::Cancelled);
+
+        // Cancelled tasks and everything downstream of them never start
+        if cancel.is_cancelled() || upstream_cancelled {
+            info!("Stopping task: {} due to cancellation", graph[node].name);
+            set_state(&mut graph[node], TaskState::Cancelled);
+            settle(graph, node, candidates);
+            return None;
+        }
+
+        let upstream_states: Vec<_> = graph
+            .neighbors_directed(node, petgraph::Incoming)
+            .map(|dep| &graph[dep].state)
+            .collect();
+        let trigger_rule = graph[node].trigger_rule;
+
+        match trigger_rule.evaluate(&upstream_states) {
+            Decision::Run => {
+                set_state(&mut graph[node], TaskState::Queued);
+                let task = graph[node].clone();
+                // Hand the outputs of the direct dependencies to the task
+                let upstream = graph
+                    .neighbors_directed(node, petgraph::Incoming)
+                    .filter_map(|dep| {
+                        let dep = &graph[dep];
+                        dep.output.clone().map(|output| (dep.name.clone(), output))
+                    })
+                    .collect();
+                Some(tokio::spawn(self.clone().execute_task(
+                    node,
+                    task,
+                    workflow_id,
+                    Arc::clone(run),
+                    upstream,
+                    cancel,
+                )))
+            }
+            Decision::Skip => {
+                info!(
+                    "Skipping task: {} as its trigger rule '{}' cannot be met",
+                    graph[node].name, trigger_rule
+                );
+                set_state(&mut graph[node], TaskState::Skipped);
+                settle(graph, node, candidates);
+                None
             }
+            Decision::UpstreamFailed => {
+                info!("Task {} cannot run as an upstream task failed", graph[node].name);
+                set_state(&mut graph[node], TaskState::UpstreamFailed);
+                settle(graph, node, candidates);
+                None
+            }
+            Decision::Wait => None,
         }
-    
-        Ok(())
-    }
-
-    // pub async fn run2(&self, workflow: &mut Workflow, pool: &PgPool) -> Result<(), io::Error> {
-    //     // Track completed tasks for resumed workflows
-    //     let mut completed = HashSet::new();
-
-    //     for node in workflow.graph.node_indices() {
-    //         match workflow.graph[node].state {
-    //             TaskState::Success => {
-    //                 // Mark already completed tasks as completed
-    //                 completed.insert(node);
-    //             }
-    //             TaskState::Failure => {
-    //                 // Reset failed or skipped tasks to Pending
-    //                 workflow.graph[node].state = TaskState::Pending;
-    //             }
-    //             _ => {} // Leave Pending and Running tasks as they are
-    //         }
-    //     }
-
-    //     loop {
-    //         let mut running_tasks: Vec<JoinHandle<Result<(NodeIndex, TaskState), String>>> = Vec::new();
-    //         let mut progress_made = false;
-
-    //         match toposort(&workflow.graph, None) {
-    //             Ok(order) => {
-    //                 for node in order {
-    //                     running_tasks.retain(|handle| !handle.is_finished());
-
-    //                     if workflow.graph[node].state == TaskState::Pending || workflow.graph[node].state == TaskState::Skipped {
-    //                         let all_deps_completed = workflow
-    //                             .graph
-    //                             .neighbors_directed(node, petgraph::Incoming)
-    //                             .all(|dep| completed.contains(&dep));
-
-    //                         if all_deps_completed {
-    //                             let task = workflow.graph[node].clone();
-    //                             let handle = tokio::spawn(Self::execute_task(node, task));
-    //                             running_tasks.push(handle);
-    //                             progress_made = true;
-    //                         } else {
-    //                             info!("Skipping task: {} due to incomplete dependencies", workflow.graph[node].name);
-    //                             workflow.graph[node].state = TaskState::Skipped;
-    //                         }
-    //                     }
-    //                 }
-
-    //                 // Collect completed tasks
-    //                 let completed_nodes = join_all(running_tasks).await;
-    //                 for result in completed_nodes {
-    //                     match result {
-    //                         Ok(Ok((node, state))) => {
-    //                             workflow.graph[node].state = state;
-    //                             completed.insert(node);
-
-    //                             // Upsert the workflow state after each task execution
-    //                             if let Err(err) = workflow.upsert_to_sql(pool, workflow_id).await {
-    //                                 error!("Failed to upsert workflow state: {}", err);
-    //                             }
-    //                         }
-    //                         Ok(Err(err)) => {
-    //                             error!("Task execution error: {}", err);
-    //                         }
-    //                         Err(join_err) => {
-    //                             error!("Join error: {}", join_err);
-    //                         }
-    //                     }
-    //                 }
-
-    //                 // If no progress was made and there are still skipped tasks, break the loop
-    //                 if !progress_made {
-    //                     break;
-    //                 }
-    //             }
-    //             Err(err) => {
-    //                 error!("Cycle detected in workflow: {:?}", err);
-    //                 return Err(io::Error::new(io::ErrorKind::Other, "Cycle detected in workflow"));
-    //             }
-    //         }
-    //     }
-
-    //     Ok(())
-    // }    
+    }
+}
+
+/// Moves a task to `state`, logging the transition if the state machine does not allow it.
+fn set_state(task: &mut Task, state: TaskState) {
+    if let Err(err) = task.state.transition(state) {
+        error!("Task '{}': {}", task.name, err);
+    }
+}
+
+/// Queues the downstream tasks of a task that just finished to be looked at again, after
+/// marking those that can no longer run if it failed.
+fn settle(graph: &mut DiGraph<Task, ()>, node: NodeIndex, candidates: &mut VecDeque<NodeIndex>) {
+    let mut finished = vec![node];
+    if graph[node].state.is_failed() {
+        finished.extend(mark_downstream_failed(graph, node));
+    }
+    for node in finished {
+        candidates.extend(graph.neighbors_directed(node, petgraph::Outgoing));
+    }
+}
+
+/// Marks the pending tasks downstream of the failed task `failed` as `UpstreamFailed`, so the
+/// workflow's status reflects the failure before the rest of the run gets to them.
+///
+/// Tasks are only marked if their trigger rule can no longer be met because of the failure,
+/// so tasks that run on failure, or whatever happens upstream, are left to run. Returns the
+/// tasks it marked.
+fn mark_downstream_failed(graph: &mut DiGraph<Task, ()>, failed: NodeIndex) -> Vec<NodeIndex> {
+    let mut marked = Vec::new();
+    let Ok(order) = toposort(&*graph, None) else {
+        return marked;
+    };
+    let mut downstream = Dfs::new(&*graph, failed);
+    let mut reachable = HashSet::new();
+    while let Some(node) = downstream.next(&*graph) {
+        reachable.insert(node);
+    }
+
+    for node in order {
+        if node == failed || !reachable.contains(&node) || graph[node].state != TaskState::Pending {
+            continue;
+        }
+        let upstream_states: Vec<_> = graph
+            .neighbors_directed(node, petgraph::Incoming)
+            .map(|dep| &graph[dep].state)
+            .collect();
+        if graph[node].trigger_rule.evaluate(&upstream_states) == Decision::UpstreamFailed {
+            info!("Marking task {} as upstream failed", graph[node].name);
+            set_state(&mut graph[node], TaskState::UpstreamFailed);
+            marked.push(node);
+        }
+    }
+    marked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trigger::TriggerRule;
+    use serde_json::Map;
+    use sqlx::postgres::PgPoolOptions;
+    use tokio::time::Duration;
+
+    type Handle = JoinHandle<Result<(NodeIndex, Task), String>>;
+
+    /// A scheduler running at most `max_parallelism` tasks at once. Its history has no database
+    /// behind it, so recording fails and is only logged.
+    fn scheduler(name: &str, max_parallelism: usize) -> Scheduler {
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(100))
+            .connect_lazy("postgres://localhost:1/thermidor")
+            .unwrap();
+        let logs = std::env::temp_dir().join(format!("thermidor-scheduler-test-{}-{}", name, std::process::id()));
+        Scheduler::new(
+            OperatorRegistry::new(pool.clone()),
+            LogStore::new(logs),
+            Cancellations::new(),
+            History::new(pool),
+            max_parallelism,
+            Pools::new(),
+        )
+    }
+
+    fn active_run(scheduler: &Scheduler) -> Arc<ActiveRun> {
+        let (started, _) = mpsc::unbounded_channel();
+        Arc::new(ActiveRun {
+            task_slots: scheduler.pools.table().slots("Workflow", usize::MAX),
+            priorities: HashMap::new(),
+            context: RunContext::manual("test", &Map::new(), Utc::now()),
+            started,
+        })
+    }
+
+    /// Builds a graph of tasks running `command`, which are not retried, with the given edges.
+    fn graph(tasks: &[(&str, &str)], edges: &[(usize, usize)]) -> (DiGraph<Task, ()>, Vec<NodeIndex>) {
+        let mut graph = DiGraph::new();
+        let nodes: Vec<_> = tasks
+            .iter()
+            .enumerate()
+            .map(|(id, (name, command))| {
+                let mut task = Task::new(id, name, command);
+                task.max_retries = 0;
+                graph.add_node(task)
+            })
+            .collect();
+        for &(from, to) in edges {
+            graph.add_edge(nodes[from], nodes[to], ());
+        }
+        (graph, nodes)
+    }
+
+    /// Looks at every candidate like a run does, returning the tasks that were started.
+    fn consider_all(
+        scheduler: &Scheduler,
+        graph: &mut DiGraph<Task, ()>,
+        run: &Arc<ActiveRun>,
+        candidates: &mut VecDeque<NodeIndex>,
+    ) -> Vec<Handle> {
+        let mut started = Vec::new();
+        while let Some(node) = candidates.pop_front() {
+            started.extend(scheduler.consider(0, graph, node, run, candidates));
+        }
+        started
+    }
+
+    /// Waits for a started task and settles it like a run does, returning the task it was.
+    async fn finish(graph: &mut DiGraph<Task, ()>, handle: Handle, candidates: &mut VecDeque<NodeIndex>) -> NodeIndex {
+        let (node, task) = handle.await.unwrap().unwrap();
+        graph[node] = task;
+        settle(graph, node, candidates);
+        node
+    }
+
+    #[tokio::test]
+    async fn the_join_of_a_diamond_waits_for_both_branches() {
+        let scheduler = scheduler("diamond", 4);
+        let run = active_run(&scheduler);
+        let (mut graph, nodes) = graph(
+            &[("extract", "true"), ("left", "true"), ("right", "true"), ("join", "true")],
+            &[(0, 1), (0, 2), (1, 3), (2, 3)],
+        );
+        let mut candidates: VecDeque<_> = nodes.iter().copied().collect();
+
+        let mut started = consider_all(&scheduler, &mut graph, &run, &mut candidates);
+        assert_eq!(started.len(), 1);
+        assert_eq!(graph[nodes[0]].state, TaskState::Queued);
+        assert_eq!(finish(&mut graph, started.remove(0), &mut candidates).await, nodes[0]);
+        let mut next: Vec<_> = candidates.iter().copied().collect();
+        next.sort();
+        assert_eq!(next, vec![nodes[1], nodes[2]]);
+
+        // Both branches start at once, and the join only once both are done
+        let mut started = consider_all(&scheduler, &mut graph, &run, &mut candidates);
+        assert_eq!(started.len(), 2);
+        finish(&mut graph, started.remove(0), &mut candidates).await;
+        assert!(consider_all(&scheduler, &mut graph, &run, &mut candidates).is_empty());
+        assert_eq!(graph[nodes[3]].state, TaskState::Pending);
+
+        finish(&mut graph, started.remove(0), &mut candidates).await;
+        let mut started = consider_all(&scheduler, &mut graph, &run, &mut candidates);
+        assert_eq!(started.len(), 1);
+        finish(&mut graph, started.remove(0), &mut candidates).await;
+        assert!(graph.node_weights().all(|task| task.state == TaskState::Success));
+    }
+
+    #[tokio::test]
+    async fn a_failed_task_marks_only_what_cannot_run_anymore_as_upstream_failed() {
+        let scheduler = scheduler("failed", 4);
+        let run = active_run(&scheduler);
+        // extract -> transform -> load -> report, transform -> cleanup (all_done)
+        let (mut graph, nodes) = graph(
+            &[("extract", "true"), ("transform", "false"), ("load", "true"), ("report", "true"), ("cleanup", "true")],
+            &[(0, 1), (1, 2), (2, 3), (1, 4)],
+        );
+        graph[nodes[4]].trigger_rule = TriggerRule::AllDone;
+        let mut candidates: VecDeque<_> = nodes.iter().copied().collect();
+
+        let mut started = consider_all(&scheduler, &mut graph, &run, &mut candidates);
+        finish(&mut graph, started.remove(0), &mut candidates).await;
+        let mut started = consider_all(&scheduler, &mut graph, &run, &mut candidates);
+        assert_eq!(finish(&mut graph, started.remove(0), &mut candidates).await, nodes[1]);
+        assert_eq!(graph[nodes[1]].state, TaskState::Failure);
+
+        // Everything downstream is marked straight away, except what runs whatever happens
+        assert_eq!(graph[nodes[2]].state, TaskState::UpstreamFailed);
+        assert_eq!(graph[nodes[3]].state, TaskState::UpstreamFailed);
+        assert_eq!(graph[nodes[4]].state, TaskState::Pending);
+
+        let mut started = consider_all(&scheduler, &mut graph, &run, &mut candidates);
+        assert_eq!(started.len(), 1);
+        assert_eq!(finish(&mut graph, started.remove(0), &mut candidates).await, nodes[4]);
+        assert_eq!(graph[nodes[4]].state, TaskState::Success);
+        assert!(consider_all(&scheduler, &mut graph, &run, &mut candidates).is_empty());
+    }
+
+    #[test]
+    fn mark_downstream_failed_leaves_other_branches_alone() {
+        // a -> b -> c, a -> d, e -> c
+        let (mut graph, nodes) = graph(
+            &[("a", "true"), ("b", "true"), ("c", "true"), ("d", "true"), ("e", "true")],
+            &[(0, 1), (1, 2), (0, 3), (4, 2)],
+        );
+        for node in [nodes[0], nodes[1]] {
+            set_state(&mut graph[node], TaskState::Queued);
+            set_state(&mut graph[node], TaskState::Running);
+        }
+        set_state(&mut graph[nodes[0]], TaskState::Success);
+        set_state(&mut graph[nodes[1]], TaskState::Failure);
+
+        assert_eq!(mark_downstream_failed(&mut graph, nodes[1]), vec![nodes[2]]);
+        assert_eq!(graph[nodes[2]].state, TaskState::UpstreamFailed);
+        assert_eq!(graph[nodes[3]].state, TaskState::Pending);
+        assert_eq!(graph[nodes[4]].state, TaskState::Pending);
+    }
+
+    #[tokio::test]
+    async fn a_task_cancelled_while_queued_never_runs_and_cancels_what_follows() {
+        let scheduler = scheduler("cancel", 1);
+        let run = active_run(&scheduler);
+        let (mut graph, nodes) = graph(&[("extract", "true"), ("load", "true")], &[(0, 1)]);
+        let mut candidates: VecDeque<_> = nodes.iter().copied().collect();
+
+        // Keep the only slot taken, so the task stays queued
+        let slot = scheduler.task_slots.acquire(1, 0).await.unwrap();
+        let mut started = consider_all(&scheduler, &mut graph, &run, &mut candidates);
+        assert_eq!(started.len(), 1);
+        sleep(Duration::from_millis(50)).await;
+        assert!(!started[0].is_finished());
+        assert_eq!(graph[nodes[0]].state, TaskState::Queued);
+
+        assert!(scheduler.cancellations.cancel_task(0, &run.context.run_id, 0));
+        finish(&mut graph, started.remove(0), &mut candidates).await;
+        drop(slot);
+        assert_eq!(graph[nodes[0]].state, TaskState::Cancelled);
+        assert!(graph[nodes[0]].attempts.is_empty());
+
+        assert!(consider_all(&scheduler, &mut graph, &run, &mut candidates).is_empty());
+        assert_eq!(graph[nodes[1]].state, TaskState::Cancelled);
+    }
 }
@@ -0,0 +1,329 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Orders waiting tasks: highest priority first, then first come, first served.
+type Ticket = (Reverse<i64>, u64);
+
+/// One set of slots in a table.
+struct Set {
+    /// Names the set in errors, e.g. `Pool 'warehouse'`.
+    name: String,
+    capacity: usize,
+    occupied: usize,
+}
+
+struct State {
+    sets: HashMap<u64, Set>,
+    next_set: u64,
+    /// Slots of each set wanted by each waiting task.
+    waiting: BTreeMap<Ticket, Vec<(u64, usize)>>,
+    next_ticket: u64,
+}
+
+impl State {
+    /// Returns the waiting tasks that get their slots if they take them now, in order.
+    ///
+    /// Slots go to waiting tasks in order. A task that wants more slots of a set than are open
+    /// keeps them for itself, so tasks behind it only get slots of the sets it is not short of,
+    /// and smaller tasks cannot keep it waiting forever.
+    fn granted(&self) -> Vec<Ticket> {
+        let mut open: HashMap<u64, usize> = self
+            .sets
+            .iter()
+            .map(|(&id, set)| (id, set.capacity.saturating_sub(set.occupied)))
+            .collect();
+        let mut granted = Vec::new();
+        for (&ticket, wanted) in &self.waiting {
+            if wanted.iter().all(|(id, slots)| *slots <= open[id]) {
+                for (id, slots) in wanted {
+                    *open.get_mut(id).unwrap() -= slots;
+                }
+                granted.push(ticket);
+            } else {
+                for (id, slots) in wanted {
+                    if *slots > open[id] {
+                        open.insert(*id, 0);
+                    }
+                }
+            }
+        }
+        granted
+    }
+
+    /// Fails if a set has fewer slots than wanted from it in total.
+    fn check_capacity(&self, wanted: &[(u64, usize)]) -> Result<(), String> {
+        for (id, slots) in wanted {
+            let set = &self.sets[id];
+            if *slots > set.capacity {
+                return Err(format!("{}: {} slots needed but only {} exist", set.name, slots, set.capacity));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sets of slots that tasks take together, such as a slot of the scheduler, one of their
+/// workflow and some of their pool. A task waits until every set it needs has enough slots
+/// open and takes them all at once, so it never holds some of them while waiting for others.
+#[derive(Clone)]
+pub struct SlotTable {
+    state: Arc<Mutex<State>>,
+    changed: Arc<Notify>,
+}
+
+/// A number of slots handed out to waiting tasks in order of priority, used to limit how
+/// many tasks run at once.
+#[derive(Clone)]
+pub struct Slots {
+    table: SlotTable,
+    set: Arc<SetHandle>,
+}
+
+/// Removes a set from its table once no `Slots` refer to it anymore.
+struct SetHandle {
+    table: SlotTable,
+    id: u64,
+}
+
+/// How much of a set of slots is in use.
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub capacity: usize,
+    pub occupied: usize,
+    pub waiting: usize,
+}
+
+impl Default for SlotTable {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                sets: HashMap::new(),
+                next_set: 0,
+                waiting: BTreeMap::new(),
+                next_ticket: 0,
+            })),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+}
+
+impl SlotTable {
+    /// Creates a table without any slots.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a set of `capacity` free slots named `name` to the table.
+    pub fn slots(&self, name: &str, capacity: usize) -> Slots {
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            let id = state.next_set;
+            state.next_set += 1;
+            state.sets.insert(id, Set { name: name.to_string(), capacity, occupied: 0 });
+            id
+        };
+        Slots {
+            table: self.clone(),
+            set: Arc::new(SetHandle { table: self.clone(), id }),
+        }
+    }
+
+    /// Waits until each of the `wanted` sets has the given number of slots free for a task
+    /// with this `priority`, then takes them all. They are given back when the returned value
+    /// is dropped.
+    ///
+    /// Fails if a set has fewer slots than asked for in total. Every set must belong to this
+    /// table.
+    pub async fn acquire(&self, wanted: &[(&Slots, usize)], priority: i64) -> Result<SlotsGuard, String> {
+        let ids: Vec<_> = wanted.iter().map(|(slots, count)| (slots.set.id, *count)).collect();
+        let ticket = {
+            let mut state = self.state.lock().unwrap();
+            state.check_capacity(&ids)?;
+            let ticket = (Reverse(priority), state.next_ticket);
+            state.next_ticket += 1;
+            state.waiting.insert(ticket, ids.clone());
+            ticket
+        };
+        let mut waiting = Waiting { table: self, ticket, done: false };
+
+        loop {
+            // Registered before looking at the slots, so changes in between wake us up
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Err(err) = state.check_capacity(&ids) {
+                    state.waiting.remove(&ticket);
+                    waiting.done = true;
+                    return Err(err);
+                }
+                if state.granted().contains(&ticket) {
+                    for (id, count) in &ids {
+                        state.sets.get_mut(id).unwrap().occupied += count;
+                    }
+                    state.waiting.remove(&ticket);
+                    waiting.done = true;
+                    drop(state);
+                    // Tasks behind may have been waiting for this one to go first
+                    self.changed.notify_waiters();
+                    let taken = wanted.iter().map(|(slots, count)| ((*slots).clone(), *count)).collect();
+                    return Ok(SlotsGuard { table: self.clone(), taken });
+                }
+            }
+            changed.await;
+        }
+    }
+}
+
+impl Slots {
+    /// Creates `capacity` free slots in a table of their own.
+    pub fn new(name: &str, capacity: usize) -> Self {
+        SlotTable::new().slots(name, capacity)
+    }
+
+    /// Changes the number of slots. Tasks keep the slots they hold if there are fewer of them,
+    /// but none are handed out until enough are given back.
+    pub fn resize(&self, capacity: usize) {
+        if let Some(set) = self.table.state.lock().unwrap().sets.get_mut(&self.set.id) {
+            set.capacity = capacity;
+        }
+        self.table.changed.notify_waiters();
+    }
+
+    /// Returns how many slots there are, how many are taken and how many tasks wait for some.
+    pub fn usage(&self) -> Usage {
+        let state = self.table.state.lock().unwrap();
+        let set = &state.sets[&self.set.id];
+        Usage {
+            capacity: set.capacity,
+            occupied: set.occupied,
+            waiting: state
+                .waiting
+                .values()
+                .filter(|wanted| wanted.iter().any(|(id, _)| *id == self.set.id))
+                .count(),
+        }
+    }
+
+    /// Waits until `slots` slots are free and no task with a higher `priority` is waiting for
+    /// them, then takes them. They are given back when the returned value is dropped.
+    ///
+    /// Fails if there are fewer slots than asked for in total.
+    pub async fn acquire(&self, slots: usize, priority: i64) -> Result<SlotsGuard, String> {
+        self.table.acquire(&[(self, slots)], priority).await
+    }
+}
+
+impl Drop for SetHandle {
+    fn drop(&mut self) {
+        self.table.state.lock().unwrap().sets.remove(&self.id);
+    }
+}
+
+/// Slots taken by a task, given back when dropped.
+pub struct SlotsGuard {
+    table: SlotTable,
+    taken: Vec<(Slots, usize)>,
+}
+
+impl Drop for SlotsGuard {
+    fn drop(&mut self) {
+        {
+            let mut state = self.table.state.lock().unwrap();
+            for (slots, count) in &self.taken {
+                if let Some(set) = state.sets.get_mut(&slots.set.id) {
+                    set.occupied = set.occupied.saturating_sub(*count);
+                }
+            }
+        }
+        self.table.changed.notify_waiters();
+    }
+}
+
+/// Keeps a task in the waiting list until it gets its slots, or gives up waiting because
+/// it was cancelled.
+struct Waiting<'a> {
+    table: &'a SlotTable,
+    ticket: Ticket,
+    done: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.table.state.lock().unwrap().waiting.remove(&self.ticket);
+            self.table.changed.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    const WAIT: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn waiting_task_holds_none_of_its_slots() {
+        let table = SlotTable::new();
+        let workflow = table.slots("Workflow", 1);
+        let pool = table.slots("Pool 'db'", 1);
+        let _pool_taken = pool.acquire(1, 0).await.unwrap();
+
+        let wanted = [(&workflow, 1), (&pool, 1)];
+        let waiting_for_pool = table.acquire(&wanted, 0);
+        tokio::pin!(waiting_for_pool);
+        assert!(timeout(WAIT, waiting_for_pool.as_mut()).await.is_err());
+        assert!(timeout(WAIT, workflow.acquire(1, 0)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn open_slots_are_kept_for_the_task_ahead() {
+        let pool = SlotTable::new().slots("Pool 'db'", 2);
+        let running = pool.acquire(1, 0).await.unwrap();
+
+        let big = pool.acquire(2, 10);
+        tokio::pin!(big);
+        assert!(timeout(WAIT, big.as_mut()).await.is_err());
+        // One slot is open, but the task ahead wants it
+        assert!(timeout(WAIT, pool.acquire(1, 0)).await.is_err());
+
+        drop(running);
+        assert!(timeout(WAIT, big).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn higher_priority_goes_first() {
+        let slots = Slots::new("Scheduler", 1);
+        let running = slots.acquire(1, 0).await.unwrap();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let mut waiting = Vec::new();
+        for (name, priority) in [("low", 1), ("high", 5), ("medium", 3)] {
+            let (slots, order) = (slots.clone(), order.clone());
+            waiting.push(tokio::spawn(async move {
+                let _taken = slots.acquire(1, priority).await.unwrap();
+                order.lock().unwrap().push(name);
+            }));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop(running);
+        for task in waiting {
+            task.await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), ["high", "medium", "low"]);
+    }
+
+    #[tokio::test]
+    async fn fails_when_asking_for_more_slots_than_exist() {
+        let pool = SlotTable::new().slots("Pool 'db'", 1);
+        let err = pool.acquire(2, 0).await.err().unwrap();
+        assert_eq!(err, "Pool 'db': 2 slots needed but only 1 exist");
+    }
+}
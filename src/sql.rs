@@ -1,3 +1,4 @@
+use crate::task::ExecutionMode;
 use sqlx::{PgPool, Row};
 use glob::glob;
 use std::error::Error;
@@ -51,12 +52,13 @@ async fn run_all_migrations(pool: &PgPool, migrations_folder: &str) -> Result<()
 }
 
 pub async fn run_migrations(pool: &PgPool, migrations_folder: &str) -> Result<(), Box<dyn Error>> {
-    run_all_migrations(&pool, migrations_folder).await?;
+    run_all_migrations(pool, migrations_folder).await?;
     println!("Database migrations completed successfully.");
     Ok(())
 }
 
 // Insert a new workflow
+#[allow(dead_code)]
 pub async fn insert_workflow(pool: &PgPool, name: &str) -> Result<i64, Box<dyn Error>> {
     let row = sqlx::query("INSERT INTO workflows.workflows (name, status) VALUES ($1, $2) RETURNING id")
         .bind(name)
@@ -67,19 +69,25 @@ pub async fn insert_workflow(pool: &PgPool, name: &str) -> Result<i64, Box<dyn E
     Ok(row.get("id"))
 }
 
-// Insert a new task
+// Insert a new task, run as `command` through a shell or as `argv` depending on its execution mode
+#[allow(dead_code)]
 pub async fn insert_task(
     pool: &PgPool,
     workflow_id: i64,
     task_name: &str,
     command: &str,
+    execution_mode: ExecutionMode,
+    argv: &[String],
 ) -> Result<i64, Box<dyn Error>> {
     let row = sqlx::query(
-        "INSERT INTO workflows.tasks (workflow_id, task_name, command) VALUES ($1, $2, $3) RETURNING id"
+        "INSERT INTO workflows.tasks (workflow_id, task_name, command, execution_mode, argv) \
+         VALUES ($1, $2, $3, $4, $5) RETURNING id"
     )
     .bind(workflow_id)
     .bind(task_name)
     .bind(command)
+    .bind(execution_mode.to_string())
+    .bind(argv)
     .fetch_one(pool)
     .await?;
 
@@ -87,6 +95,7 @@ pub async fn insert_task(
 }
 
 // Update task status
+#[allow(dead_code)]
 pub async fn update_task_status(
     pool: &PgPool,
     task_id: i64,
@@ -100,7 +109,8 @@ pub async fn update_task_status(
     Ok(())
 }
 
-pub async fn get_workflow_id(pool: &PgPool, workflow_name: String) -> Result<(i64), Box<dyn Error>> {
+#[allow(dead_code)]
+pub async fn get_workflow_id(pool: &PgPool, workflow_name: String) -> Result<i64, Box<dyn Error>> {
     let row = sqlx::query("SELECT id FROM workflows.workflows WHERE name = $1")
         .bind(workflow_name)
         .fetch_one(pool)
@@ -111,28 +121,45 @@ pub async fn get_workflow_id(pool: &PgPool, workflow_name: String) -> Result<(i6
     Ok(id)
 }
 
+/// A task as stored in `workflows.tasks`, as returned by `get_tasks`.
+#[allow(dead_code)]
+pub struct TaskRow {
+    pub id: i64,
+    pub name: String,
+    pub command: String,
+    pub execution_mode: ExecutionMode,
+    pub argv: Vec<String>,
+    pub state: String,
+}
+
 // Get tasks by workflow ID
-pub async fn get_tasks(pool: &PgPool, workflow_id: i64) -> Result<Vec<(i64, String, String, String)>, Box<dyn Error>> {
-    let rows = sqlx::query("SELECT id, task_name, command, state FROM workflows.tasks WHERE workflow_id = $1")
-        .bind(workflow_id)
-        .fetch_all(pool)
-        .await?;
+#[allow(dead_code)]
+pub async fn get_tasks(pool: &PgPool, workflow_id: i64) -> Result<Vec<TaskRow>, Box<dyn Error>> {
+    let rows = sqlx::query(
+        "SELECT id, task_name, command, execution_mode, argv, state FROM workflows.tasks WHERE workflow_id = $1"
+    )
+    .bind(workflow_id)
+    .fetch_all(pool)
+    .await?;
 
-    let tasks = rows
-        .into_iter()
-        .map(|row| {
-            let id: i64 = row.get("id");
-            let name: String = row.get("task_name");
-            let command: String = row.get("command");
-            let state: String = row.get("state");
-            (id, name, command, state)
-        })
-        .collect();
+    let mut tasks = Vec::with_capacity(rows.len());
+    for row in rows {
+        let execution_mode: String = row.get("execution_mode");
+        tasks.push(TaskRow {
+            id: row.get("id"),
+            name: row.get("task_name"),
+            command: row.get("command"),
+            execution_mode: execution_mode.parse()?,
+            argv: row.get("argv"),
+            state: row.get("state"),
+        });
+    }
 
     Ok(tasks)
 }
 
 // Get dependencies for a workflow
+#[allow(dead_code)]
 pub async fn get_dependencies(
     pool: &PgPool,
     workflow_id: i64
@@ -155,4 +182,4 @@ pub async fn get_dependencies(
         .collect();
 
     Ok(dependencies)
-}
\ No newline at end of file
+}
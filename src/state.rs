@@ -1,15 +1,95 @@
 use serde::{Serialize, Deserialize};
 use std::str::FromStr;
 use std::fmt;
+use std::io;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, )]
 pub enum TaskState {
+    /// Waiting for its upstream tasks.
     Pending,
+    /// Ready to run and waiting to be started.
+    Queued,
     Running,
+    /// An attempt failed and the task waits before the next one.
+    UpForRetry,
     Success,
     Failure,
+    /// The last attempt ran past the task's timeout.
+    TimedOut,
+    /// Not run because its trigger rule could not be met.
     Skipped,
-    Stopped
+    /// Not run because an upstream task failed.
+    UpstreamFailed,
+    /// Cancelled while running or before it started, or downstream of a cancelled task.
+    #[serde(alias = "Stopped")]
+    Cancelled,
+}
+
+/// A state change the task state machine does not allow.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IllegalTransition {
+    pub from: TaskState,
+    pub to: TaskState,
+}
+
+impl fmt::Display for IllegalTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Illegal task state transition from {} to {}", self.from, self.to)
+    }
+}
+
+impl std::error::Error for IllegalTransition {}
+
+impl From<IllegalTransition> for io::Error {
+    fn from(err: IllegalTransition) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+impl TaskState {
+    /// Returns whether the task is done for the current run.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            TaskState::Success
+                | TaskState::Failure
+                | TaskState::TimedOut
+                | TaskState::Skipped
+                | TaskState::UpstreamFailed
+                | TaskState::Cancelled
+        )
+    }
+
+    /// Returns whether the task failed, or could not run because an upstream task failed.
+    pub fn is_failed(&self) -> bool {
+        matches!(self, TaskState::Failure | TaskState::TimedOut | TaskState::UpstreamFailed)
+    }
+
+    /// Returns whether a task may go from this state to `next`.
+    ///
+    /// Finished tasks can only go back to `Pending`, when they are run again.
+    pub fn can_transition_to(&self, next: &TaskState) -> bool {
+        use TaskState::*;
+
+        match (self, next) {
+            (Pending, Queued | Skipped | UpstreamFailed | Cancelled) => true,
+            // A task that cannot be started at all fails straight away
+            (Queued, Running | Failure | Cancelled) => true,
+            (Running, Success | Failure | TimedOut | UpForRetry | Cancelled) => true,
+            (UpForRetry, Running | Cancelled) => true,
+            (from, Pending) => from.is_finished(),
+            _ => false,
+        }
+    }
+
+    /// Moves to `next`, or returns an error and stays put if that is not a legal transition.
+    pub fn transition(&mut self, next: TaskState) -> Result<(), IllegalTransition> {
+        if !self.can_transition_to(&next) {
+            return Err(IllegalTransition { from: self.clone(), to: next });
+        }
+        *self = next;
+        Ok(())
+    }
 }
 
 impl FromStr for TaskState {
@@ -18,11 +98,15 @@ impl FromStr for TaskState {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "Pending" => Ok(TaskState::Pending),
+            "Queued" => Ok(TaskState::Queued),
             "Running" => Ok(TaskState::Running),
+            "UpForRetry" => Ok(TaskState::UpForRetry),
             "Success" => Ok(TaskState::Success),
             "Failure" => Ok(TaskState::Failure),
+            "TimedOut" => Ok(TaskState::TimedOut),
             "Skipped" => Ok(TaskState::Skipped),
-            "Stopped" => Ok(TaskState::Stopped),
+            "UpstreamFailed" => Ok(TaskState::UpstreamFailed),
+            "Cancelled" | "Stopped" => Ok(TaskState::Cancelled),
             _ => Err(format!("Invalid task state: {}", s)),
         }
     }
@@ -32,12 +116,60 @@ impl fmt::Display for TaskState {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let state = match self {
             TaskState::Pending => "Pending",
+            TaskState::Queued => "Queued",
             TaskState::Running => "Running",
+            TaskState::UpForRetry => "UpForRetry",
             TaskState::Success => "Success",
             TaskState::Failure => "Failure",
+            TaskState::TimedOut => "TimedOut",
             TaskState::Skipped => "Skipped",
-            TaskState::Stopped => "Stopped",
+            TaskState::UpstreamFailed => "UpstreamFailed",
+            TaskState::Cancelled => "Cancelled",
         };
         write!(f, "{}", state)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TaskState::{self, *};
+
+    const ALL: [TaskState; 10] =
+        [Pending, Queued, Running, UpForRetry, Success, Failure, TimedOut, Skipped, UpstreamFailed, Cancelled];
+
+    #[test]
+    fn a_run_goes_through_the_legal_transitions() {
+        let mut state = Pending;
+        for next in [Queued, Running, UpForRetry, Running, Success, Pending] {
+            assert!(state.transition(next.clone()).is_ok(), "{} to {}", state, next);
+        }
+        assert_eq!(state, Pending);
+    }
+
+    #[test]
+    fn only_finished_tasks_go_back_to_pending() {
+        for state in ALL {
+            assert_eq!(state.can_transition_to(&Pending), state.is_finished(), "{} to Pending", state);
+        }
+    }
+
+    #[test]
+    fn finished_tasks_do_not_change_otherwise() {
+        for from in ALL.iter().filter(|state| state.is_finished()) {
+            for to in ALL.iter().filter(|state| **state != Pending) {
+                assert!(!from.can_transition_to(to), "{} to {}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn illegal_transitions_leave_the_state_alone() {
+        let mut state = Pending;
+        let err = state.transition(Success).unwrap_err();
+        assert_eq!(err.to_string(), "Illegal task state transition from Pending to Success");
+        assert_eq!(state, Pending);
+        assert!(!Queued.can_transition_to(&Success));
+        assert!(!UpForRetry.can_transition_to(&Success));
+        assert!(Queued.can_transition_to(&Failure));
+    }
+}
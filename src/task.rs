@@ -1,131 +1,557 @@
 use crate::state::TaskState;
-use tokio::process::Command;
-use std::process::Output;
+use crate::logs::{LogStore, LogStream};
+use crate::retry::RetryPolicy;
+use crate::limits::ResourceLimits;
+use crate::priority::WeightRule;
+use crate::trigger::TriggerRule;
+use crate::template::{self, RunContext};
+use crate::operator::{Operator, OperatorContext, Outcome};
+use tokio_util::sync::CancellationToken;
 use chrono::{DateTime, Utc};
-use tokio::time::{sleep, Duration, timeout, Instant};
-use tracing::{info, error};
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{info, warn, error};
 use serde::{Serialize, Deserialize};
+use std::str::FromStr;
+use std::fmt;
+use std::io;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Controls how a task's command is turned into a child process.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecutionMode {
+    /// Runs `command` through `/bin/sh -c`, so pipes, quoting and redirects work.
+    #[default]
+    Shell,
+    /// Runs `argv[0]` with the remaining `argv` entries as arguments, without a shell.
+    Exec,
+    /// Splits `command` on whitespace, as Thermidor originally did.
+    Legacy,
+}
+
+impl FromStr for ExecutionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shell" => Ok(ExecutionMode::Shell),
+            "exec" => Ok(ExecutionMode::Exec),
+            "legacy" => Ok(ExecutionMode::Legacy),
+            _ => Err(format!("Invalid execution mode: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ExecutionMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self {
+            ExecutionMode::Shell => "shell",
+            ExecutionMode::Exec => "exec",
+            ExecutionMode::Legacy => "legacy",
+        };
+        write!(f, "{}", mode)
+    }
+}
+
+/// The record of one attempt at running a task.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attempt {
+    /// Attempt number, starting at 1.
+    pub number: usize,
+    pub start_time: DateTime<Utc>,
+    pub end_time: Option<DateTime<Utc>>,
+    /// How the attempt ended, or `None` while it is running.
+    pub outcome: Option<Outcome>,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub failure_reason: Option<String>,
+    /// Where the attempt's stdout and stderr are stored.
+    pub stdout_log: PathBuf,
+    pub stderr_log: PathBuf,
+}
+
+impl Attempt {
+    /// Records how the attempt ended.
+    fn finish(&mut self, outcome: &Outcome) {
+        self.end_time = Some(Utc::now());
+        self.exit_code = outcome.exit_code();
+        self.signal = outcome.signal();
+        self.failure_reason = outcome.failure_reason();
+        self.outcome = Some(outcome.clone());
+    }
+}
+
+fn default_kill_grace_period() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_kind() -> String {
+    "shell".to_string()
+}
+
+fn default_pool_slots() -> usize {
+    1
+}
+
+fn default_priority_weight() -> i64 {
+    1
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Task {
     pub id: usize,
     pub name: String,
+    /// Which operator runs the task, e.g. `shell`.
+    #[serde(default = "default_kind")]
+    pub kind: String,
+    /// Operator-specific configuration.
+    #[serde(default)]
+    pub config: serde_json::Value,
     pub command: String,
+    #[serde(default)]
+    pub mode: ExecutionMode,
+    #[serde(default)]
+    pub argv: Vec<String>,
+    /// Extra environment variables for the process.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Directory the process is started in, instead of the server's.
+    #[serde(default)]
+    pub working_dir: Option<PathBuf>,
+    /// Start the process with only the variables in `env`, instead of inheriting the server's.
+    #[serde(default)]
+    pub clear_env: bool,
+    /// Payload written to the process's stdin, which is otherwise empty.
+    #[serde(default)]
+    pub stdin: Option<String>,
+    /// Resource limits applied to the process.
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    /// When the task runs, given the states of its upstream tasks.
+    #[serde(default)]
+    pub trigger_rule: TriggerRule,
+    /// Pool the task takes slots from while it runs, shared with tasks of other workflows.
+    #[serde(default)]
+    pub pool: Option<String>,
+    /// Number of slots of `pool` the task takes.
+    #[serde(default = "default_pool_slots")]
+    pub pool_slots: usize,
+    /// How urgent the task is compared to others waiting for slots, see `weight_rule`.
+    #[serde(default = "default_priority_weight")]
+    pub priority_weight: i64,
+    /// Whether the weights of upstream or downstream tasks add to the task's priority.
+    #[serde(default)]
+    pub weight_rule: WeightRule,
     pub state: TaskState,
     pub max_retries: usize,
+    /// Overrides the workflow's default retry policy for this task.
+    #[serde(default)]
+    pub retry_policy: Option<RetryPolicy>,
     pub retry_count: usize,
     pub timeout_duration: Duration,
+    /// Time between SIGTERM and SIGKILL when the task's process group is terminated on timeout.
+    #[serde(default = "default_kill_grace_period")]
+    pub kill_grace_period: Duration,
     pub start_time: Option<DateTime<Utc>>,
     pub end_time: Option<DateTime<Utc>>,
+    /// Every attempt made so far, oldest first.
+    #[serde(default)]
+    pub attempts: Vec<Attempt>,
+    /// Outcome of the most recent attempt.
+    #[serde(default)]
+    pub last_outcome: Option<Outcome>,
+    /// Small JSON result published by the most recent attempt, such as a shell task's
+    /// `$THERMIDOR_OUTPUT` file or a SQL task's rows affected. Downstream tasks can read it.
+    #[serde(default)]
+    pub output: Option<serde_json::Value>,
 }
 
 #[derive(Deserialize)]
 pub struct NewTask {
     pub id: usize,
     pub name: String,
+    #[serde(default = "default_kind")]
+    pub kind: String,
+    #[serde(default)]
+    pub config: serde_json::Value,
+    #[serde(default)]
     pub command: String,
+    #[serde(default)]
+    pub mode: ExecutionMode,
+    #[serde(default)]
+    pub argv: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub working_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub clear_env: bool,
+    #[serde(default)]
+    pub stdin: Option<String>,
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    #[serde(default)]
+    pub trigger_rule: TriggerRule,
+    #[serde(default)]
+    pub pool: Option<String>,
+    #[serde(default = "default_pool_slots")]
+    pub pool_slots: usize,
+    #[serde(default = "default_priority_weight")]
+    pub priority_weight: i64,
+    #[serde(default)]
+    pub weight_rule: WeightRule,
+    pub max_retries: Option<usize>,
+    pub retry_policy: Option<RetryPolicy>,
+    pub timeout_duration: Option<Duration>,
+    pub kill_grace_period: Option<Duration>,
     pub dependencies: Vec<usize>,
 }
 
+impl NewTask {
+    /// Builds the task described by this request, without its dependencies.
+    pub fn to_task(&self) -> Task {
+        let mut task = Task::new(self.id, &self.name, &self.command)
+            .with_mode(self.mode.clone(), self.argv.clone());
+        task.kind = self.kind.clone();
+        task.config = self.config.clone();
+        task.env = self.env.clone();
+        task.working_dir = self.working_dir.clone();
+        task.clear_env = self.clear_env;
+        task.stdin = self.stdin.clone();
+        task.limits = self.limits.clone();
+        task.trigger_rule = self.trigger_rule;
+        task.pool = self.pool.clone();
+        task.pool_slots = self.pool_slots;
+        task.priority_weight = self.priority_weight;
+        task.weight_rule = self.weight_rule;
+        if let Some(max_retries) = self.max_retries {
+            task.max_retries = max_retries;
+        }
+        task.retry_policy = self.retry_policy.clone();
+        if let Some(timeout_duration) = self.timeout_duration {
+            task.timeout_duration = timeout_duration;
+        }
+        if let Some(kill_grace_period) = self.kill_grace_period {
+            task.kill_grace_period = kill_grace_period;
+        }
+        task
+    }
+}
+
 impl Task {
     /// Creates a new task with the specified id, name, command, and max retries.
     pub fn new(id: usize, name: &str, command: &str) -> Self {
         Self {
             id,
             name: name.to_string(),
+            kind: default_kind(),
+            config: serde_json::Value::Null,
             command: command.to_string(),
+            mode: ExecutionMode::Shell,
+            argv: Vec::new(),
+            env: BTreeMap::new(),
+            working_dir: None,
+            clear_env: false,
+            stdin: None,
+            limits: ResourceLimits::default(),
+            trigger_rule: TriggerRule::default(),
+            pool: None,
+            pool_slots: default_pool_slots(),
+            priority_weight: default_priority_weight(),
+            weight_rule: WeightRule::default(),
             state: TaskState::Pending,
             max_retries: 5,
+            retry_policy: None,
             retry_count: 0,
             timeout_duration: Duration::from_secs(86400), // 24 hours
+            kill_grace_period: default_kill_grace_period(),
             start_time: None,
             end_time: None,
+            attempts: Vec::new(),
+            last_outcome: None,
+            output: None,
+        }
+    }
+
+    /// Sets the execution mode and, for `ExecutionMode::Exec`, the argv to run.
+    pub fn with_mode(mut self, mode: ExecutionMode, argv: Vec<String>) -> Self {
+        self.mode = mode;
+        self.argv = argv;
+        self
+    }
+
+    /// Returns a copy of the task with the templates in its command, argv, env, stdin and
+    /// config rendered with `variables`.
+    pub fn render(&self, variables: &serde_json::Value) -> Result<Task, String> {
+        let mut task = self.clone();
+        task.command = template::render(&self.command, variables)?;
+        for arg in task.argv.iter_mut() {
+            *arg = template::render(arg, variables)?;
+        }
+        for value in task.env.values_mut() {
+            *value = template::render(value, variables)?;
+        }
+        if let Some(stdin) = &self.stdin {
+            task.stdin = Some(template::render(stdin, variables)?);
+        }
+        task.config = template::render_value(&self.config, variables)?;
+        Ok(task)
+    }
+
+    /// Clears what the previous run left in the task, so that a new run starts it afresh.
+    pub fn reset(&mut self) {
+        // Tasks left queued or running by a server that stopped mid-run are reset too
+        self.state = TaskState::Pending;
+        self.retry_count = 0;
+        self.start_time = None;
+        self.end_time = None;
+        self.attempts.clear();
+        self.last_outcome = None;
+        self.output = None;
+    }
+
+    /// Puts a task that a server stopped mid-run left queued, running or waiting for a retry
+    /// back to `Pending`, so a resumed run starts it again. An attempt it did not finish is
+    /// dropped, as it will be made again.
+    pub fn requeue(&mut self) {
+        // Loaded from a saved workflow rather than changed by a run, so not a transition
+        self.state = TaskState::Pending;
+        self.attempts.retain(|attempt| attempt.end_time.is_some());
+        self.end_time = None;
+    }
+
+    /// Copies what a run did with the task, its state, attempts and output, from the run's
+    /// copy of it.
+    pub fn copy_run_state(&mut self, from: &Task) {
+        self.state = from.state.clone();
+        self.retry_count = from.retry_count;
+        self.start_time = from.start_time;
+        self.end_time = from.end_time;
+        self.attempts = from.attempts.clone();
+        self.last_outcome = from.last_outcome.clone();
+        self.output = from.output.clone();
+    }
+
+    /// Marks the task as cancelled and returns the error `execute` reports.
+    fn stop(&mut self, workflow_id: usize, run: &RunContext, logs: &LogStore) -> io::Error {
+        if let Err(err) = self.state.transition(TaskState::Cancelled) {
+            return err.into();
         }
+        self.end_time = Some(Utc::now());
+        warn!("Task '{}' was cancelled.", self.name);
+        logs.close_live(workflow_id, &run.run_id, self.id);
+        io::Error::new(io::ErrorKind::Interrupted, format!("Task '{}' was cancelled", self.name))
     }
 
-    /// Executes the task asynchronously with retry logic and prints stdout/stderr.
-    pub async fn execute(&mut self) -> Result<Output, std::io::Error> {
-        self.state = TaskState::Running;
+    /// Renders the task's templates and runs the next attempt with `operator`, storing its
+    /// stdout/stderr in `logs` and the result it publishes in `output`.
+    async fn run_attempt(
+        &mut self,
+        operator: &dyn Operator,
+        workflow_id: usize,
+        run: &RunContext,
+        upstream: &BTreeMap<String, serde_json::Value>,
+        logs: &LogStore,
+        cancel: &CancellationToken,
+    ) -> Outcome {
+        let attempt = self.retry_count + 1;
+        let (stdout, mut stderr) = match logs.open_attempt(workflow_id, &run.run_id, self.id, attempt).await {
+            Ok(writers) => writers,
+            Err(err) => {
+                return Outcome::Error {
+                    message: format!("Failed to open logs: {}", err),
+                    retryable: true,
+                };
+            }
+        };
+
+        let rendered = match self.render(&run.variables(self, upstream)) {
+            Ok(rendered) => rendered,
+            Err(message) => {
+                let _ = stderr.write(format!("{}\n", message).as_bytes()).await;
+                return Outcome::Error { message, retryable: false };
+            }
+        };
+
+        let mut output = None;
+        let ctx = OperatorContext {
+            task: &rendered,
+            workflow_id,
+            attempt,
+            stdout,
+            stderr,
+            cancel,
+            upstream,
+            output_file: logs.output_path(workflow_id, &run.run_id, self.id, attempt),
+            output: &mut output,
+        };
+        let outcome = operator.run(ctx).await;
+        self.output = output;
+        outcome
+    }
+
+    /// Executes the task asynchronously with `operator` and retry logic, storing each attempt's
+    /// stdout/stderr in `logs`.
+    ///
+    /// `run` describes the workflow run the task is part of and `upstream` holds the outputs of
+    /// the tasks it depends on, keyed by task name. Both are available to the task's templates.
+    /// Failed attempts are retried according to the task's retry policy, or the default policy
+    /// if it has none, with the task `UpForRetry` in between. Cancelling `cancel` stops the
+    /// running attempt, stops further retries and leaves the task `Cancelled`.
+    ///
+    /// The task must be `Queued`; it ends up `Success`, `Failure`, `TimedOut` or `Cancelled`.
+    pub async fn execute(
+        &mut self,
+        operator: &dyn Operator,
+        workflow_id: usize,
+        run: &RunContext,
+        upstream: &BTreeMap<String, serde_json::Value>,
+        logs: &LogStore,
+        cancel: &CancellationToken,
+    ) -> Result<(), io::Error> {
+        let policy = self.retry_policy.clone().unwrap_or_default();
+        self.state.transition(TaskState::Running)?;
         self.start_time = Some(Utc::now()); // Set the task start time
 
         loop {
+            if cancel.is_cancelled() {
+                return Err(self.stop(workflow_id, run, logs));
+            }
+
             let start_time = Instant::now(); // Track precise execution duration for this attempt
+            let attempt = self.retry_count + 1;
 
             info!(
                 "Executing task {}: {} (Attempt {}/{}) at {:?}",
                 self.id,
                 self.name,
-                self.retry_count + 1,
+                attempt,
                 self.max_retries + 1,
                 start_time // Log the global task start time
             );
 
-            let parts: Vec<&str> = self.command.split_whitespace().collect();
-            let (cmd, args) = parts.split_first().unwrap_or((&"", &[]));
-
-            // Execute the command with a timeout
-            let output = timeout(self.timeout_duration, Command::new(cmd).args(args).output()).await;
+            self.attempts.push(Attempt {
+                number: attempt,
+                start_time: Utc::now(),
+                end_time: None,
+                outcome: None,
+                exit_code: None,
+                signal: None,
+                failure_reason: None,
+                stdout_log: logs.log_path(workflow_id, &run.run_id, self.id, attempt, LogStream::Stdout),
+                stderr_log: logs.log_path(workflow_id, &run.run_id, self.id, attempt, LogStream::Stderr),
+            });
+            let outcome = self.run_attempt(operator, workflow_id, run, upstream, logs, cancel).await;
+            if let Some(record) = self.attempts.last_mut() {
+                record.finish(&outcome);
+            }
 
             let end_time = Instant::now(); // Measure end time for this attempt
             let duration = end_time - start_time;
 
-            match output {
-                Ok(Ok(result)) => {
-                    if result.status.success() {
-                        self.state = TaskState::Success;
-                        self.end_time = Some(Utc::now()); // Set the task end time
-                        info!(
-                            "Task '{}' completed successfully in {} seconds.",
-                            self.name,
-                            duration.as_secs()
-                        );
-                        info!("stdout: {}", String::from_utf8_lossy(&result.stdout));
-                        return Ok(result);
-                    } else {
-                        self.state = TaskState::Failure;
-                        error!(
-                            "Task '{}' failed with exit code: {:?} in {} seconds.",
-                            self.name,
-                            result.status.code(),
-                            duration.as_secs()
-                        );
-                    }
+            self.last_outcome = Some(outcome.clone());
+            let failed_state = if outcome == Outcome::TimedOut { TaskState::TimedOut } else { TaskState::Failure };
+
+            let retryable = match outcome {
+                Outcome::Succeeded => {
+                    self.state.transition(TaskState::Success)?;
+                    self.end_time = Some(Utc::now()); // Set the task end time
+                    info!(
+                        "Task '{}' completed successfully in {} seconds.",
+                        self.name,
+                        duration.as_secs()
+                    );
+                    logs.close_live(workflow_id, &run.run_id, self.id);
+                    return Ok(());
                 }
-                Ok(Err(err)) => {
-                    self.state = TaskState::Failure;
+                Outcome::Failed { exit_code, .. } => {
+                    error!(
+                        "Task '{}' failed with exit code: {:?} in {} seconds.",
+                        self.name,
+                        exit_code,
+                        duration.as_secs()
+                    );
+                    policy.retries_exit_code(exit_code)
+                }
+                Outcome::LimitExceeded { resource, exit_code, .. } => {
+                    error!(
+                        "Task '{}' exceeded its {} limit and exited with code {:?} in {} seconds.",
+                        self.name,
+                        resource,
+                        exit_code,
+                        duration.as_secs()
+                    );
+                    policy.retries_exit_code(exit_code)
+                }
+                Outcome::Error { message, retryable } => {
                     error!(
                         "Failed to execute task '{}': {} in {} seconds.",
                         self.name,
-                        err,
+                        message,
+                        duration.as_secs()
+                    );
+                    retryable
+                }
+                Outcome::QueryFailed { sqlstate, message, retryable } => {
+                    error!(
+                        "Task '{}' failed with SQLSTATE {}: {} in {} seconds.",
+                        self.name,
+                        sqlstate.as_deref().unwrap_or("unknown"),
+                        message,
                         duration.as_secs()
                     );
+                    retryable
                 }
-                Err(_) => {
-                    self.state = TaskState::Failure;
+                Outcome::UnexpectedResponse { status, message, retryable } => {
+                    error!(
+                        "Task '{}' got HTTP status {}: {} in {} seconds.",
+                        self.name,
+                        status,
+                        message,
+                        duration.as_secs()
+                    );
+                    retryable
+                }
+                Outcome::TimedOut => {
                     error!(
                         "Task '{}' timed out after {} seconds.",
                         self.name,
                         self.timeout_duration.as_secs()
                     );
+                    policy.retry_on_timeout
                 }
-            }
+                Outcome::Cancelled => {
+                    return Err(self.stop(workflow_id, run, logs));
+                }
+            };
 
             self.retry_count += 1;
 
-            if self.retry_count > self.max_retries {
-                self.end_time = Some(Utc::now()); // Set the task end time on max retry failure
-                error!("Task '{}' failed after {} attempts.", self.name, self.retry_count);
+            if !retryable || self.retry_count > self.max_retries {
+                self.state.transition(failed_state)?;
+                self.end_time = Some(Utc::now()); // Set the task end time on final failure
+                if retryable {
+                    error!("Task '{}' failed after {} attempts.", self.name, self.retry_count);
+                } else {
+                    error!("Task '{}' failed with a non-retryable error after {} attempts.", self.name, self.retry_count);
+                }
+                logs.close_live(workflow_id, &run.run_id, self.id);
                 break;
             }
 
-            let retry_delay = Duration::from_secs(2u64.pow(self.retry_count as u32));
-            info!("Retrying task '{}' in {:?} seconds...", self.name, retry_delay);
-            sleep(retry_delay).await;
+            self.state.transition(TaskState::UpForRetry)?;
+            let retry_delay = policy.delay(self.retry_count);
+            info!("Retrying task '{}' in {:?}...", self.name, retry_delay);
+            tokio::select! {
+                _ = sleep(retry_delay) => {}
+                _ = cancel.cancelled() => return Err(self.stop(workflow_id, run, logs)),
+            }
+            self.state.transition(TaskState::Running)?;
         }
 
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
+        Err(io::Error::other(
             format!("Task '{}' failed after {} retries", self.name, self.retry_count),
         ))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,232 @@
+use crate::task::Task;
+use crate::run::RunTrigger;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashSet};
+
+/// What a workflow run is about. Its fields are available to task templates.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunContext {
+    pub run_id: String,
+    /// The date the run is for, which is not necessarily when it runs.
+    pub logical_date: DateTime<Utc>,
+    /// Start of the period of data the run processes.
+    pub data_interval_start: DateTime<Utc>,
+    /// End of the period of data the run processes.
+    pub data_interval_end: DateTime<Utc>,
+    pub workflow: String,
+    pub params: Map<String, Value>,
+    pub trigger: RunTrigger,
+}
+
+impl RunContext {
+    /// Describes a run of a workflow triggered by hand for `logical_date`, covering the data
+    /// up to that moment.
+    pub fn manual(workflow: &str, params: &Map<String, Value>, logical_date: DateTime<Utc>) -> Self {
+        Self {
+            run_id: run_id(RunTrigger::Manual, logical_date),
+            logical_date,
+            data_interval_start: logical_date,
+            data_interval_end: logical_date,
+            workflow: workflow.to_string(),
+            params: params.clone(),
+            trigger: RunTrigger::Manual,
+        }
+    }
+
+    /// Describes a run of a workflow started by its schedule for the data interval from
+    /// `start` to `end`. The logical date is the start of the interval.
+    pub fn scheduled(
+        workflow: &str,
+        params: &Map<String, Value>,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            run_id: run_id(RunTrigger::Scheduled, start),
+            logical_date: start,
+            data_interval_start: start,
+            data_interval_end: end,
+            workflow: workflow.to_string(),
+            params: params.clone(),
+            trigger: RunTrigger::Scheduled,
+        }
+    }
+
+    /// Describes a run of a workflow started by a backfill for the past data interval from
+    /// `start` to `end`. The logical date is the start of the interval, as for scheduled runs.
+    pub fn backfill(
+        workflow: &str,
+        params: &Map<String, Value>,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            run_id: run_id(RunTrigger::Backfill, start),
+            trigger: RunTrigger::Backfill,
+            ..Self::scheduled(workflow, params, start, end)
+        }
+    }
+
+    /// Returns the variables templates of `task` can use, given the outputs of its upstream tasks.
+    pub fn variables(&self, task: &Task, upstream: &BTreeMap<String, Value>) -> Value {
+        json!({
+            "run_id": self.run_id,
+            "ds": self.logical_date.format("%Y-%m-%d").to_string(),
+            "ds_nodash": self.logical_date.format("%Y%m%d").to_string(),
+            "ts": self.logical_date.to_rfc3339(),
+            "logical_date": self.logical_date.to_rfc3339(),
+            "data_interval_start": self.data_interval_start.to_rfc3339(),
+            "data_interval_end": self.data_interval_end.to_rfc3339(),
+            "workflow": self.workflow,
+            "task": task.name,
+            "task_id": task.id,
+            "params": self.params,
+            "upstream": upstream,
+        })
+    }
+}
+
+/// Returns the id of the run started by `trigger` for `logical_date`, e.g.
+/// `scheduled__2024-01-01T00:00:00`. Fractions of a second are only included if there are any.
+pub fn run_id(trigger: RunTrigger, logical_date: DateTime<Utc>) -> String {
+    format!("{}__{}", trigger, logical_date.format("%Y-%m-%dT%H:%M:%S%.f"))
+}
+
+/// Returns `run_id`, or if a run already has that id, `run_id` with the first `__<n>` suffix
+/// from 2 up that no run has, e.g. `backfill__2024-01-01T00:00:00__2` for the second run over
+/// an interval. `taken` holds the ids of the existing runs starting with `run_id`.
+pub fn unique_run_id(run_id: &str, taken: &HashSet<String>) -> String {
+    if !taken.contains(run_id) {
+        return run_id.to_string();
+    }
+    (2..)
+        .map(|n| format!("{}__{}", run_id, n))
+        .find(|candidate| !taken.contains(candidate))
+        .expect("some suffix is free")
+}
+
+/// Parses a date given as `YYYY-MM-DD` (midnight UTC) or as an RFC 3339 timestamp.
+pub fn parse_date(date: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(day) = NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        return Ok(day.and_hms_opt(0, 0, 0).expect("midnight is valid").and_utc());
+    }
+    DateTime::parse_from_rfc3339(date)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|_| format!("Invalid date '{}', expected YYYY-MM-DD or RFC 3339", date))
+}
+
+/// Replaces every `{{ name }}` in `template` with the value of that variable. Nested values
+/// are reached with dots, e.g. `{{ params.table }}`. Strings are inserted as they are, other
+/// values as JSON.
+pub fn render(template: &str, variables: &Value) -> Result<String, String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| format!("Unclosed '{{{{' in template: {}", template))?;
+        let name = after[..end].trim();
+
+        let value = name
+            .split('.')
+            .try_fold(variables, |value, key| match value {
+                Value::Object(map) => map.get(key),
+                Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+                _ => None,
+            })
+            .ok_or_else(|| format!("Undefined template variable '{}'", name))?;
+        match value {
+            Value::String(s) => rendered.push_str(s),
+            other => rendered.push_str(&other.to_string()),
+        }
+
+        rest = &after[end + 2..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Renders every string inside a JSON value, such as an operator's config.
+pub fn render_value(value: &Value, variables: &Value) -> Result<Value, String> {
+    Ok(match value {
+        Value::String(s) => Value::String(render(s, variables)?),
+        Value::Array(items) => Value::Array(
+            items.iter().map(|item| render_value(item, variables)).collect::<Result<_, _>>()?,
+        ),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, item)| Ok((key.clone(), render_value(item, variables)?)))
+                .collect::<Result<_, String>>()?,
+        ),
+        other => other.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn renders_nested_variables() {
+        let variables = json!({ "ds": "2024-03-01", "params": { "table": "orders", "ids": [4, 7] } });
+        let rendered = render("load {{ params.table }} for {{ds}} from {{ params.ids.1 }}", &variables);
+        assert_eq!(rendered.unwrap(), "load orders for 2024-03-01 from 7");
+    }
+
+    #[test]
+    fn renders_values_other_than_strings_as_json() {
+        let variables = json!({ "upstream": { "extract": { "rows": 3, "tables": ["a", "b"] } } });
+        let rendered = render("{{ upstream.extract.rows }} {{ upstream.extract.tables }}", &variables);
+        assert_eq!(rendered.unwrap(), r#"3 ["a","b"]"#);
+    }
+
+    #[test]
+    fn fails_on_undefined_variables_and_unclosed_braces() {
+        let variables = json!({ "params": {} });
+        assert_eq!(render("{{ params.table }}", &variables).unwrap_err(), "Undefined template variable 'params.table'");
+        assert_eq!(render("{{ params", &variables).unwrap_err(), "Unclosed '{{' in template: {{ params");
+        assert_eq!(render("no variables }}", &variables).unwrap(), "no variables }}");
+    }
+
+    #[test]
+    fn renders_every_string_of_a_value() {
+        let config = json!({ "url": "https://example.com/{{ ds }}", "retries": 3, "tags": ["{{ workflow }}"] });
+        let variables = json!({ "ds": "2024-03-01", "workflow": "sales" });
+        let rendered = render_value(&config, &variables).unwrap();
+        assert_eq!(rendered, json!({ "url": "https://example.com/2024-03-01", "retries": 3, "tags": ["sales"] }));
+    }
+
+    #[test]
+    fn manual_runs_render_their_logical_date() {
+        let logical_date = Utc.with_ymd_and_hms(2024, 3, 1, 2, 30, 0).unwrap();
+        let context = RunContext::manual("sales", &Map::new(), logical_date);
+        let variables = context.variables(&Task::new(0, "load", ""), &BTreeMap::new());
+        let rendered = render("{{ run_id }} {{ ds }} {{ ds_nodash }} {{ workflow }}.{{ task }}", &variables);
+        assert_eq!(rendered.unwrap(), "manual__2024-03-01T02:30:00 2024-03-01 20240301 sales.load");
+    }
+
+    #[test]
+    fn run_ids_taken_by_earlier_runs_get_a_suffix() {
+        let run_id = "backfill__2024-03-01T00:00:00";
+        assert_eq!(unique_run_id(run_id, &HashSet::new()), run_id);
+        let taken: HashSet<_> = [run_id.to_string(), format!("{}__2", run_id)].into_iter().collect();
+        assert_eq!(unique_run_id(run_id, &taken), "backfill__2024-03-01T00:00:00__3");
+    }
+
+    #[test]
+    fn scheduled_runs_render_their_logical_date() {
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 2, 30, 0).unwrap();
+        let end = Utc.with_ymd_and_hms(2024, 3, 2, 2, 30, 0).unwrap();
+        let context = RunContext::scheduled("sales", &Map::new(), start, end);
+        let variables = context.variables(&Task::new(0, "load", ""), &BTreeMap::new());
+        let rendered = render("{{ run_id }} {{ ds_nodash }} {{ data_interval_end }}", &variables);
+        assert_eq!(rendered.unwrap(), "scheduled__2024-03-01T02:30:00 20240301 2024-03-02T02:30:00+00:00");
+    }
+}
@@ -0,0 +1,159 @@
+use crate::state::TaskState;
+use serde::{Serialize, Deserialize};
+use std::str::FromStr;
+use std::fmt;
+
+/// Decides from the states of a task's upstream tasks whether it runs.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerRule {
+    /// Run once every upstream task succeeded.
+    #[default]
+    AllSuccess,
+    /// Run once every upstream task finished, whatever its state.
+    AllDone,
+    /// Run as soon as one upstream task failed, e.g. to clean up or alert.
+    OneFailed,
+    /// Run as soon as one upstream task succeeded.
+    OneSuccess,
+    /// Run once every upstream task finished without failing, so skipped branches are tolerated.
+    NoneFailed,
+}
+
+/// What to do with a task whose upstream tasks are in some set of states.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Run,
+    /// The rule can no longer be met in this run.
+    Skip,
+    /// The rule can no longer be met because an upstream task failed.
+    UpstreamFailed,
+    /// Upstream tasks are still to finish.
+    Wait,
+}
+
+impl TriggerRule {
+    /// Evaluates the rule against the current states of a task's upstream tasks.
+    /// Tasks without upstream tasks always run.
+    pub fn evaluate(&self, upstream: &[&TaskState]) -> Decision {
+        if upstream.is_empty() {
+            return Decision::Run;
+        }
+        let all_done = upstream.iter().all(|state| state.is_finished());
+        let any_failed = upstream.iter().any(|state| state.is_failed());
+        let any_success = upstream.iter().any(|state| **state == TaskState::Success);
+        // Why a rule that can no longer be met is not met
+        let not_met = if any_failed { Decision::UpstreamFailed } else { Decision::Skip };
+
+        match self {
+            TriggerRule::AllSuccess => {
+                if upstream.iter().all(|state| **state == TaskState::Success) {
+                    Decision::Run
+                } else if any_failed {
+                    Decision::UpstreamFailed
+                } else if upstream.iter().any(|state| state.is_finished() && **state != TaskState::Success) {
+                    Decision::Skip
+                } else {
+                    Decision::Wait
+                }
+            }
+            TriggerRule::AllDone if all_done => Decision::Run,
+            TriggerRule::OneFailed if any_failed => Decision::Run,
+            TriggerRule::OneFailed if all_done => Decision::Skip,
+            TriggerRule::OneSuccess if any_success => Decision::Run,
+            TriggerRule::NoneFailed if any_failed => Decision::UpstreamFailed,
+            TriggerRule::NoneFailed if all_done => Decision::Run,
+            _ if all_done => not_met,
+            _ => Decision::Wait,
+        }
+    }
+}
+
+impl FromStr for TriggerRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all_success" => Ok(TriggerRule::AllSuccess),
+            "all_done" => Ok(TriggerRule::AllDone),
+            "one_failed" => Ok(TriggerRule::OneFailed),
+            "one_success" => Ok(TriggerRule::OneSuccess),
+            "none_failed" => Ok(TriggerRule::NoneFailed),
+            _ => Err(format!("Invalid trigger rule: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for TriggerRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rule = match self {
+            TriggerRule::AllSuccess => "all_success",
+            TriggerRule::AllDone => "all_done",
+            TriggerRule::OneFailed => "one_failed",
+            TriggerRule::OneSuccess => "one_success",
+            TriggerRule::NoneFailed => "none_failed",
+        };
+        write!(f, "{}", rule)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TaskState::*;
+
+    #[test]
+    fn all_success_waits_for_running_upstream_tasks() {
+        assert_eq!(TriggerRule::AllSuccess.evaluate(&[&Success, &Running]), Decision::Wait);
+        assert_eq!(TriggerRule::AllSuccess.evaluate(&[&Success, &Pending]), Decision::Wait);
+        assert_eq!(TriggerRule::AllSuccess.evaluate(&[&Skipped, &Running]), Decision::Skip);
+    }
+
+    #[test]
+    fn all_success() {
+        let rule = TriggerRule::AllSuccess;
+        assert_eq!(rule.evaluate(&[&Success, &Success]), Decision::Run);
+        assert_eq!(rule.evaluate(&[&Success, &Failure]), Decision::UpstreamFailed);
+        assert_eq!(rule.evaluate(&[&UpstreamFailed, &Running]), Decision::UpstreamFailed);
+        assert_eq!(rule.evaluate(&[&Success, &Cancelled]), Decision::Skip);
+    }
+
+    #[test]
+    fn all_done() {
+        let rule = TriggerRule::AllDone;
+        assert_eq!(rule.evaluate(&[&Success, &Failure, &Skipped]), Decision::Run);
+        assert_eq!(rule.evaluate(&[&Failure, &UpForRetry]), Decision::Wait);
+    }
+
+    #[test]
+    fn one_failed() {
+        let rule = TriggerRule::OneFailed;
+        assert_eq!(rule.evaluate(&[&TimedOut, &Running]), Decision::Run);
+        assert_eq!(rule.evaluate(&[&Success, &Running]), Decision::Wait);
+        assert_eq!(rule.evaluate(&[&Success, &Skipped]), Decision::Skip);
+    }
+
+    #[test]
+    fn one_success() {
+        let rule = TriggerRule::OneSuccess;
+        assert_eq!(rule.evaluate(&[&Success, &Running]), Decision::Run);
+        assert_eq!(rule.evaluate(&[&Failure, &Running]), Decision::Wait);
+        assert_eq!(rule.evaluate(&[&Failure, &Skipped]), Decision::UpstreamFailed);
+        assert_eq!(rule.evaluate(&[&Skipped, &Cancelled]), Decision::Skip);
+    }
+
+    #[test]
+    fn none_failed() {
+        let rule = TriggerRule::NoneFailed;
+        assert_eq!(rule.evaluate(&[&Success, &Skipped]), Decision::Run);
+        assert_eq!(rule.evaluate(&[&Success, &Queued]), Decision::Wait);
+        assert_eq!(rule.evaluate(&[&Failure, &Running]), Decision::UpstreamFailed);
+    }
+
+    #[test]
+    fn tasks_without_upstream_tasks_run() {
+        for rule in ["all_success", "all_done", "one_failed", "one_success", "none_failed"] {
+            assert_eq!(rule.parse::<TriggerRule>().unwrap().evaluate(&[]), Decision::Run);
+        }
+    }
+}
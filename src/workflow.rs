@@ -1,30 +1,102 @@
-use crate::task::Task;
+use crate::task::{ExecutionMode, Task};
 use crate::scheduler::Scheduler;
+use crate::retry::RetryPolicy;
+use crate::limits::ResourceLimits;
+use crate::trigger::TriggerRule;
+use crate::priority::WeightRule;
+use crate::schedule::Schedule;
+use crate::params::ParamsSchema;
+use crate::template::RunContext;
+use chrono::Utc;
 use petgraph::graph::{DiGraph, NodeIndex};
 use sqlx::{PgPool, Row};
+use sqlx::types::Json;
+use sqlx::postgres::types::PgInterval;
+use tokio::time::Duration;
 use std::fs::File;
 use std::io::{self, Write, Read};
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use serde::{Serialize, Deserialize};
 use tokio::sync::Mutex;
 use tokio::task;
 use tracing::info;
 
+/// The set of loaded workflows shared between the scheduler and the HTTP API.
+pub type SharedWorkflows = Arc<Mutex<Vec<Arc<Mutex<Workflow>>>>>;
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Workflow {
+    /// Name of the workflow, available to task templates as `{{ workflow }}`.
+    #[serde(default)]
+    pub name: String,
+    /// Parameters available to task templates as `{{ params.<name> }}`.
+    #[serde(default)]
+    pub params: serde_json::Map<String, serde_json::Value>,
+    /// Params that runs triggered through the API may be given, see `ParamsSchema`. Any
+    /// params are accepted if not set.
+    #[serde(default)]
+    pub params_schema: Option<ParamsSchema>,
     pub graph: DiGraph<Task, ()>,
     pub resumed: bool,
+    /// Retry policy for tasks that don't define their own.
+    #[serde(default)]
+    pub default_retry_policy: RetryPolicy,
+    /// Maximum number of tasks of the workflow running at once, unlimited if not set.
+    #[serde(default)]
+    pub max_active_tasks: Option<usize>,
+    /// Cron expression or preset the workflow runs on, see `Schedule`. Workflows without one
+    /// run once when the server starts.
+    #[serde(default)]
+    pub schedule: Option<String>,
+    /// Whether the schedule intervals missed while Thermidor was down, or while runs took
+    /// longer than the schedule, are all run, oldest first. Only the latest one is run if not.
+    #[serde(default)]
+    pub catchup: bool,
+    /// Maximum number of runs of the workflow going on at once, unlimited if not set.
+    #[serde(default)]
+    pub max_active_runs: Option<usize>,
+    /// Id of the run the state of the tasks is from, the latest one to have started.
+    #[serde(default)]
+    pub run_id: Option<String>,
+    /// Ids of the runs going on, oldest first.
+    #[serde(skip)]
+    pub active_runs: Vec<String>,
+    /// Ids of the runs triggered but not yet recorded in the run history, which no other run
+    /// may be given meanwhile.
+    #[serde(skip)]
+    pub starting_runs: Vec<String>,
+    /// File the workflow and the state of its latest run are saved to.
+    #[serde(skip)]
+    pub save_path: String,
 }
 
 impl Workflow {
     pub fn new() -> Self {
         Self {
+            name: String::new(),
+            params: serde_json::Map::new(),
+            params_schema: None,
             graph: DiGraph::new(),
             resumed: false,
+            default_retry_policy: RetryPolicy::default(),
+            max_active_tasks: None,
+            schedule: None,
+            catchup: false,
+            max_active_runs: None,
+            run_id: None,
+            active_runs: Vec::new(),
+            starting_runs: Vec::new(),
+            save_path: String::new(),
         }
     }
 
+    /// Parses the workflow's schedule, if it has one.
+    pub fn schedule(&self) -> Result<Option<Schedule>, String> {
+        self.schedule.as_deref().map(str::parse).transpose()
+    }
+
     /// Adds a task to the workflow and returns its NodeIndex.
     pub fn add_task(&mut self, task: Task) -> NodeIndex {
         self.graph.add_node(task)
@@ -52,32 +124,102 @@ impl Workflow {
         Ok(workflow)
     }
 
+    /// Loads a workflow, its tasks and their dependencies from the `workflows` schema.
+    #[allow(dead_code)]
     pub async fn load_from_sql(pool: &PgPool, workflow_id: i64) -> io::Result<Self> {
         // Fetch the workflow data
-        let row = sqlx::query("SELECT id, name FROM workflows.workflows WHERE id = $1")
+        let row = sqlx::query("SELECT id, name, retry_policy, params, params_schema, max_active_tasks, schedule, catchup, max_active_runs FROM workflows.workflows WHERE id = $1")
             .bind(workflow_id)
             .fetch_one(pool)
             .await
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            .map_err(|e| io::Error::other(e.to_string()))?;
 
         let mut workflow = Workflow::new();
+        workflow.name = row.get("name");
+        let params: Option<Json<serde_json::Map<String, serde_json::Value>>> = row.get("params");
+        if let Some(Json(params)) = params {
+            workflow.params = params;
+        }
+        let params_schema: Option<Json<ParamsSchema>> = row.get("params_schema");
+        workflow.params_schema = params_schema.map(|schema| schema.0);
+        let default_retry_policy: Option<Json<RetryPolicy>> = row.get("retry_policy");
+        if let Some(Json(policy)) = default_retry_policy {
+            workflow.default_retry_policy = policy;
+        }
+        let max_active_tasks: Option<i32> = row.get("max_active_tasks");
+        workflow.max_active_tasks = max_active_tasks.map(|max| max.max(1) as usize);
+        workflow.schedule = row.get("schedule");
+        workflow.catchup = row.get("catchup");
+        let max_active_runs: Option<i32> = row.get("max_active_runs");
+        workflow.max_active_runs = max_active_runs.map(|max| max.max(1) as usize);
 
         // Fetch tasks for this workflow
         let task_rows = sqlx::query(
-            "SELECT id, task_name, command FROM workflows.tasks WHERE workflow_id = $1"
+            "SELECT task_idx, task_name, kind, config, command, execution_mode, argv, env, working_dir, clear_env, stdin, limits, trigger_rule, pool, pool_slots, \
+             priority_weight, weight_rule, \
+             max_retries, retry_policy, timeout_duration, kill_grace_period \
+             FROM workflows.tasks WHERE workflow_id = $1"
         )
         .bind(workflow_id)
         .fetch_all(pool)
         .await
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        .map_err(|e| io::Error::other(e.to_string()))?;
 
         // Add tasks to the workflow
         for task_row in task_rows {
-            let task_id: i64 = task_row.get("id");
+            let task_id: i32 = task_row.get("task_idx");
             let task_name: String = task_row.get("task_name");
+            let kind: String = task_row.get("kind");
+            let config: Option<serde_json::Value> = task_row.get("config");
             let task_command: String = task_row.get("command");
+            let execution_mode: String = task_row.get("execution_mode");
+            let argv: Vec<String> = task_row.get("argv");
+            let env: Json<BTreeMap<String, String>> = task_row.get("env");
+            let working_dir: Option<String> = task_row.get("working_dir");
+            let clear_env: bool = task_row.get("clear_env");
+            let stdin: Option<String> = task_row.get("stdin");
+            let limits: Option<Json<ResourceLimits>> = task_row.get("limits");
+            let trigger_rule: String = task_row.get("trigger_rule");
+            let task_pool: Option<String> = task_row.get("pool");
+            let pool_slots: i32 = task_row.get("pool_slots");
+            let priority_weight: i32 = task_row.get("priority_weight");
+            let weight_rule: String = task_row.get("weight_rule");
+            let max_retries: i32 = task_row.get("max_retries");
+            let retry_policy: Option<Json<RetryPolicy>> = task_row.get("retry_policy");
+            let timeout_duration: Option<PgInterval> = task_row.get("timeout_duration");
+            let kill_grace_period: PgInterval = task_row.get("kill_grace_period");
+
+            let mode = execution_mode
+                .parse::<ExecutionMode>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let trigger_rule = trigger_rule
+                .parse::<TriggerRule>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let weight_rule = weight_rule
+                .parse::<WeightRule>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
-            workflow.add_task_dynamically(task_id as usize, &task_name, &task_command);
+            let node = workflow.add_task_dynamically(task_id as usize, &task_name, &task_command);
+            workflow.graph[node].kind = kind;
+            workflow.graph[node].config = config.unwrap_or_default();
+            workflow.graph[node].mode = mode;
+            workflow.graph[node].argv = argv;
+            workflow.graph[node].env = env.0;
+            workflow.graph[node].working_dir = working_dir.map(PathBuf::from);
+            workflow.graph[node].clear_env = clear_env;
+            workflow.graph[node].stdin = stdin;
+            workflow.graph[node].limits = limits.map(|limits| limits.0).unwrap_or_default();
+            workflow.graph[node].trigger_rule = trigger_rule;
+            workflow.graph[node].pool = task_pool;
+            workflow.graph[node].pool_slots = pool_slots.max(0) as usize;
+            workflow.graph[node].priority_weight = priority_weight.into();
+            workflow.graph[node].weight_rule = weight_rule;
+            workflow.graph[node].max_retries = max_retries as usize;
+            workflow.graph[node].retry_policy = retry_policy.map(|policy| policy.0);
+            if let Some(timeout_duration) = timeout_duration {
+                workflow.graph[node].timeout_duration = interval_to_duration(&timeout_duration);
+            }
+            workflow.graph[node].kill_grace_period = interval_to_duration(&kill_grace_period);
         }
 
         // Fetch dependencies for this workflow
@@ -87,12 +229,12 @@ impl Workflow {
         .bind(workflow_id)
         .fetch_all(pool)
         .await
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        .map_err(|e| io::Error::other(e.to_string()))?;
 
         // Add dependencies to the workflow
         for dependency_row in dependency_rows {
-            let from_task_idx: i64 = dependency_row.get("from_task_idx");
-            let to_task_idx: i64 = dependency_row.get("to_task_idx");
+            let from_task_idx: i32 = dependency_row.get("from_task_idx");
+            let to_task_idx: i32 = dependency_row.get("to_task_idx");
 
             workflow.add_dependency_dynamically(from_task_idx as usize, to_task_idx as usize)
                 .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
@@ -123,6 +265,13 @@ impl Workflow {
     
 }
 
+/// Converts a Postgres interval to a duration, counting a month as 30 days.
+fn interval_to_duration(interval: &PgInterval) -> Duration {
+    let days = interval.months as i64 * 30 + interval.days as i64;
+    let micros = days * 86_400_000_000 + interval.microseconds;
+    Duration::from_micros(micros.max(0) as u64)
+}
+
 pub struct WorkflowBuilder {
     workflow: Workflow,
     task_indices: HashMap<String, NodeIndex>,
@@ -155,6 +304,25 @@ impl WorkflowBuilder {
         self
     }
 
+    /// Sets the retry policy used by tasks that don't have their own.
+    #[allow(dead_code)]
+    pub fn default_retry_policy(&mut self, policy: RetryPolicy) -> &mut Self {
+        self.workflow.default_retry_policy = policy;
+        self
+    }
+
+    /// Sets the maximum number of retries and the retry policy of a task.
+    #[allow(dead_code)]
+    pub fn retry_policy(&mut self, name: &str, max_retries: usize, policy: RetryPolicy) -> &mut Self {
+        if let Some(&node) = self.task_indices.get(name) {
+            self.workflow.graph[node].max_retries = max_retries;
+            self.workflow.graph[node].retry_policy = Some(policy);
+        } else {
+            eprintln!("Error: Task not found: {}", name);
+        }
+        self
+    }
+
     /// Provides a cloned copy of the workflow for saving or other operations.
     pub fn get_workflow(&self) -> Workflow {
         self.workflow.clone()
@@ -182,32 +350,38 @@ pub async fn schedule_workflow() -> Vec<(Arc<Mutex<Workflow>>, String)> {
     let mut workflows = Vec::new();
 
     for (workflow_name, tasks, dependencies) in workflows_data {
-        let workflow = Arc::new(Mutex::new(
-            match Workflow::load_from_json(workflow_name) {
-                Ok(wf) => {
-                    info!("Loaded workflow from '{}'", workflow_name);
-                    wf
-                }
-                Err(_) => {
-                    info!("Creating a new workflow for '{}'", workflow_name);
-                    let mut builder = WorkflowBuilder::new();
-    
-                    // Add tasks
-                    for (id, name, command) in tasks {
-                        builder.add_task(id, name, command);
-                    }
+        let mut workflow = match Workflow::load_from_json(workflow_name) {
+            Ok(wf) => {
+                info!("Loaded workflow from '{}'", workflow_name);
+                wf
+            }
+            Err(_) => {
+                info!("Creating a new workflow for '{}'", workflow_name);
+                let mut builder = WorkflowBuilder::new();
     
-                    // Add dependencies
-                    for (from, to) in dependencies {
-                        builder.add_dependency(from, to);
-                    }
+                // Add tasks
+                for (id, name, command) in tasks {
+                    builder.add_task(id, name, command);
+                }
     
-                    builder.get_workflow()
+                // Add dependencies
+                for (from, to) in dependencies {
+                    builder.add_dependency(from, to);
                 }
-            },
-        ));
     
-        workflows.push((workflow, workflow_name.to_string()));
+                builder.get_workflow()
+            }
+        };
+        if workflow.name.is_empty() {
+            // Name workflows after their file, e.g. `workflow1`
+            workflow.name = Path::new(workflow_name)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+        }
+        workflow.save_path = workflow_name.to_string();
+    
+        workflows.push((Arc::new(Mutex::new(workflow)), workflow_name.to_string()));
     }
 
     workflows
@@ -215,32 +389,93 @@ pub async fn schedule_workflow() -> Vec<(Arc<Mutex<Workflow>>, String)> {
 
 
 // Function to start the workflows using the scheduler
-async fn _start_workflow(workflows_with_paths: Vec<(Arc<Mutex<Workflow>>, String)>) {
-    let scheduler = Scheduler::new();
-
-    for (workflow, save_path) in workflows_with_paths {
-        let workflow_clone = Arc::clone(&workflow);
-        let scheduler_clone = scheduler.clone();
-        let save_path_clone = save_path.clone();
-
+async fn _start_workflow(
+    workflows_with_paths: Vec<(Arc<Mutex<Workflow>>, String)>,
+    scheduler: Scheduler,
+) {
+    for (workflow_id, (workflow, save_path)) in workflows_with_paths.into_iter().enumerate() {
+        let (name, schedule, saved_run, context) = {
+            let workflow = workflow.lock().await;
+            (
+                workflow.name.clone(),
+                workflow.schedule(),
+                workflow.run_id.clone(),
+                RunContext::manual(&workflow.name, &workflow.params, Utc::now()),
+            )
+        };
+        // Where the schedule left off is looked up before runs interrupted by the restart are
+        // marked as failed, so their intervals are run again
+        let after = match &schedule {
+            Ok(Some(schedule)) => Some(scheduler.schedule_start(&name, schedule).await),
+            _ => None,
+        };
+        let interrupted = scheduler.fail_interrupted_runs(&name).await;
+        // The tasks' states only carry on under the run they were saved from, if the restart
+        // interrupted it
+        let resumed = match saved_run.filter(|run_id| interrupted.contains(run_id)) {
+            Some(run_id) if matches!(schedule, Ok(None)) => scheduler.recorded_run(&name, &run_id).await,
+            _ => None,
+        };
+
+        let scheduler = scheduler.clone();
         task::spawn(async move {
-            let mut workflow_guard = workflow_clone.lock().await;
-            if let Err(err) = scheduler_clone.run(&mut *workflow_guard, &save_path_clone).await {
-                eprintln!("Error running workflow '{}': {}", save_path_clone, err);
+            match (schedule, after) {
+                // Scheduled workflows wait for their schedule, others run once now
+                (Ok(Some(schedule)), Some(after)) => {
+                    scheduler.run_on_schedule(workflow_id, &workflow, &save_path, schedule, after).await;
+                }
+                (Err(err), _) => eprintln!("Not running workflow '{}': {}", save_path, err),
+                _ => {
+                    let result = match resumed {
+                        Some(resumed) => scheduler.resume(workflow_id, &workflow, &save_path, resumed).await,
+                        None => scheduler.run(workflow_id, &workflow, &save_path, context).await,
+                    };
+                    if let Err(err) = result {
+                        eprintln!("Error running workflow '{}': {}", save_path, err);
+                    }
+                }
             }
         });
     }
 }
 
 /// Initializes and returns the workflows wrapped in `Arc<Mutex<_>>` for shared access.
-pub async fn start_workflows() -> Arc<Mutex<Vec<Arc<Mutex<Workflow>>>>> {
+pub async fn start_workflows(scheduler: Scheduler) -> SharedWorkflows {
     // Schedule workflows by loading them from configuration or creating new ones.
     let workflows_with_paths = schedule_workflow().await;
 
     // Start the workflows.
-    _start_workflow(workflows_with_paths.clone()).await;
+    _start_workflow(workflows_with_paths.clone(), scheduler).await;
 
     // Extract workflows and wrap them for shared access.
     let workflows: Vec<_> = workflows_with_paths.into_iter().map(|(wf, _)| wf).collect();
     Arc::new(Mutex::new(workflows))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::BackoffStrategy;
+
+    #[test]
+    fn builder_sets_retry_policies() {
+        let default_policy = RetryPolicy { strategy: BackoffStrategy::Fixed, ..RetryPolicy::default() };
+        let extract_policy = RetryPolicy { retryable_exit_codes: vec![75], ..RetryPolicy::default() };
+        let workflow = WorkflowBuilder::new()
+            .add_task(1, "extract", "echo extract")
+            .add_task(2, "load", "echo load")
+            .add_dependency("extract", "load")
+            .default_retry_policy(default_policy.clone())
+            .retry_policy("extract", 2, extract_policy.clone())
+            .get_workflow();
+
+        assert_eq!(workflow.default_retry_policy, default_policy);
+        let task = |name: &str| workflow.graph.node_weights().find(|task| task.name == name).unwrap().clone();
+        let (extract, load) = (task("extract"), task("load"));
+        assert_eq!(extract.max_retries, 2);
+        assert_eq!(extract.retry_policy, Some(extract_policy));
+        // Tasks without a policy of their own fall back to the workflow's default
+        assert_eq!(load.max_retries, Task::new(2, "load", "").max_retries);
+        assert_eq!(load.retry_policy, None);
+    }
+}